@@ -0,0 +1,133 @@
+//! Measures how long a `ServerFlow` takes to send many small responses over TLS, with and
+//! without batching (see `ServerFlowOptions::max_batch_size`).
+//!
+//! Run with `cargo bench --bench batching`.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use imap_codec::imap_types::response::{Data, Greeting};
+use imap_flow::{
+    server::{ServerFlow, ServerFlowEvent, ServerFlowOptions},
+    stream::AnyStream,
+};
+use tokio::io::AsyncReadExt;
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName},
+    TlsAcceptor, TlsConnector,
+};
+
+/// Number of small responses sent per run.
+const RESPONSES: u32 = 10_000;
+/// Number of runs per batch size.
+const RUNS: u32 = 10;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let (acceptor, connector) = tls();
+
+    for max_batch_size in [0, 4 * 1024, 16 * 1024] {
+        let mut total = Duration::ZERO;
+        let mut bytes_written = 0;
+
+        for _ in 0..RUNS {
+            let (elapsed, bytes) = run(&acceptor, &connector, max_batch_size).await;
+            total += elapsed;
+            bytes_written = bytes;
+        }
+
+        println!(
+            "max_batch_size = {max_batch_size:>5}: {:>10.2?} per {RESPONSES} responses ({bytes_written} bytes on the wire)",
+            total / RUNS,
+        );
+    }
+}
+
+// Returns the time until the client received all responses and the number of TLS bytes.
+async fn run(
+    acceptor: &TlsAcceptor,
+    connector: &TlsConnector,
+    max_batch_size: usize,
+) -> (Duration, u64) {
+    let (server_stream, client_stream) = tokio::io::duplex(64 * 1024);
+
+    let client = {
+        let connector = connector.clone();
+
+        tokio::task::spawn(async move {
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(server_name, client_stream).await.unwrap();
+
+            // Wait for the greeting and all responses.
+            let mut buffer = vec![0; 64 * 1024];
+            let mut lines = 0;
+            while lines < RESPONSES + 1 {
+                let byte_count = stream.read(&mut buffer).await.unwrap();
+                assert_ne!(byte_count, 0);
+                lines += buffer[..byte_count]
+                    .iter()
+                    .filter(|byte| **byte == b'\n')
+                    .count() as u32;
+            }
+
+            Instant::now()
+        })
+    };
+
+    let server_stream = acceptor.accept(server_stream).await.unwrap();
+    let options = ServerFlowOptions {
+        max_batch_size,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    let start = Instant::now();
+
+    for number in 1..=RESPONSES {
+        server.enqueue_data(Data::Exists(number)).unwrap();
+    }
+
+    let mut sent = 0;
+    while sent < RESPONSES {
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::ResponseSent { .. } => sent += 1,
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    let end = client.await.unwrap();
+
+    (end - start, server.statistics().bytes_written)
+}
+
+fn tls() -> (TlsAcceptor, TlsConnector) {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let certificate_der = Certificate(certificate.serialize_der().unwrap());
+    let private_key_der = PrivateKey(certificate.serialize_private_key_der());
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate_der.clone()], private_key_der)
+        .unwrap();
+
+    let mut root_store = RootCertStore::empty();
+    root_store.add(&certificate_der).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    (
+        TlsAcceptor::from(Arc::new(server_config)),
+        TlsConnector::from(Arc::new(client_config)),
+    )
+}
@@ -18,10 +18,12 @@ async fn main() {
             .unwrap();
     println!("received greeting: {greeting:?}");
 
-    let handle = client.enqueue_command(Command {
-        tag: Tag::try_from("A1").unwrap(),
-        body: CommandBody::login("Al¹cE", "pa²²w0rd").unwrap(),
-    });
+    let handle = client
+        .enqueue_command(Command {
+            tag: Tag::try_from("A1").unwrap(),
+            body: CommandBody::login("Al¹cE", "pa²²w0rd").unwrap(),
+        })
+        .unwrap();
 
     loop {
         match client.progress().await.unwrap() {
@@ -36,6 +38,7 @@ async fn main() {
                 handle: got_handle,
                 command,
                 status,
+                ..
             } => {
                 println!("command rejected: {got_handle:?}, {command:?}, {status:?}");
                 assert_eq!(handle, got_handle);
@@ -27,7 +27,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     client.enqueue_command(Command {
         tag: tag.clone(),
         body: CommandBody::authenticate(AuthMechanism::Login),
-    });
+    })?;
 
     let mut authenticate_data = VecDeque::from([
         AuthenticateData::Continue(Secret::new(b"alice".to_vec())),
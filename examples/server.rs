@@ -30,7 +30,9 @@ async fn main() {
             ServerFlowEvent::CommandReceived { command } => {
                 println!("command received: {command:?}");
                 handle = Some(
-                    server.enqueue_status(Status::no(Some(command.tag), None, "...").unwrap()),
+                    server
+                        .enqueue_status(Status::no(Some(command.tag), None, "...").unwrap())
+                        .unwrap(),
                 );
             }
             ServerFlowEvent::ResponseSent {
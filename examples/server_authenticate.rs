@@ -76,7 +76,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             ServerFlowEvent::CommandReceived { command } => {
                 server.enqueue_status(
                     Status::no(Some(command.tag), None, "Please use AUTHENTICATE").unwrap(),
-                );
+                )?;
             }
             _ => {}
         }
@@ -3,8 +3,9 @@ use std::{net::SocketAddr, sync::Arc};
 use colored::Colorize;
 use imap_codec::imap_types::{
     bounded_static::ToBoundedStatic,
+    command::CommandBody,
     core::Text,
-    response::{Code, Status},
+    response::{Code, Greeting, Status},
 };
 use imap_flow::{
     client::{ClientFlow, ClientFlowError, ClientFlowEvent, ClientFlowOptions},
@@ -17,7 +18,7 @@ use tokio_rustls::{
     rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
     TlsAcceptor, TlsConnector,
 };
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
 use crate::{
     config::{Bind, Connect, Identity, Service},
@@ -27,6 +28,8 @@ use crate::{
 const LITERAL_ACCEPT_TEXT: &str = "proxy: Literal accepted by proxy";
 const LITERAL_REJECT_TEXT: &str = "proxy: Literal rejected by proxy";
 const COMMAND_REJECTED_TEXT: &str = "proxy: Command rejected by server";
+const DUPLICATE_TAG_TEXT: &str = "proxy: AUTHENTICATE with duplicate tag rejected by proxy";
+const MULTIAPPEND_REJECT_TEXT: &str = "proxy: MULTIAPPEND rejected by proxy";
 
 #[derive(Debug, Error)]
 pub enum ProxyError {
@@ -187,9 +190,12 @@ impl State for ConnectedState {}
 
 impl Proxy<ConnectedState> {
     pub async fn start_conversion(self) {
-        let (mut proxy_to_server, mut greeting) = {
+        let (mut proxy_to_server, greeting) = {
             // TODO: Read options from config
-            let options = ClientFlowOptions { crlf_relaxed: true };
+            let options = ClientFlowOptions {
+                crlf_relaxed: true,
+                ..Default::default()
+            };
 
             let result = ClientFlow::receive_greeting(self.state.proxy_to_server, options).await;
 
@@ -203,6 +209,7 @@ impl Proxy<ConnectedState> {
         };
         trace!(greeting=%format!("{:?}", greeting).blue(), role = "s2p", "<--| Received greeting");
 
+        let mut greeting = Greeting::from(greeting);
         util::filter_capabilities_in_greeting(&mut greeting);
 
         let (mut client_to_proxy, greeting) = {
@@ -229,7 +236,7 @@ impl Proxy<ConnectedState> {
         loop {
             let control_flow = tokio::select! {
                 event = client_to_proxy.progress() => {
-                    handle_client_event(event, &mut proxy_to_server)
+                    handle_client_event(event, &mut client_to_proxy, &mut proxy_to_server)
                 }
                 event = proxy_to_server.progress() => {
                     handle_server_event(event, &mut client_to_proxy)
@@ -245,6 +252,7 @@ impl Proxy<ConnectedState> {
 
 fn handle_client_event(
     error: Result<ServerFlowEvent, ServerFlowError>,
+    client_to_proxy: &mut ServerFlow,
     proxy_to_server: &mut ClientFlow,
 ) -> ControlFlow {
     let event = match error {
@@ -258,6 +266,12 @@ fn handle_client_event(
             }
             | ServerFlowError::LiteralTooLong {
                 ref discarded_bytes,
+            }
+            | ServerFlowError::Literal8Rejected {
+                ref discarded_bytes,
+            }
+            | ServerFlowError::TooManyMalformedCommands {
+                ref discarded_bytes,
             }),
         ) => {
             error!(role = "c2p", %error, ?discarded_bytes, "Discard client message");
@@ -267,6 +281,18 @@ fn handle_client_event(
             error!(role = "c2p", %error, "Connection terminated");
             return ControlFlow::Abort;
         }
+        Err(error @ ServerFlowError::Closed) => {
+            error!(role = "c2p", %error, "Connection closed");
+            return ControlFlow::Abort;
+        }
+        Err(error @ ServerFlowError::LiteralDecisionPending) => {
+            error!(role = "c2p", %error, "Literal decision pending");
+            return ControlFlow::Abort;
+        }
+        Err(error @ ServerFlowError::LiteralReader(_)) => {
+            error!(role = "p2c", %error, "Connection closed");
+            return ControlFlow::Abort;
+        }
     };
 
     match event {
@@ -279,8 +305,39 @@ fn handle_client_event(
         }
         ServerFlowEvent::CommandReceived { command } => {
             trace!(command=%format!("{:?}", command).red(), role = "c2p", "|--> Received command");
-            let _handle = proxy_to_server.enqueue_command(command);
-            // TODO: log handle
+            if let Err(error) = proxy_to_server.enqueue_command(command) {
+                error!(role = "p2s", %error, "Failed to forward command");
+                return ControlFlow::Abort;
+            }
+        }
+        ServerFlowEvent::DuplicateTagReceived { command } => {
+            if let CommandBody::Authenticate { .. } = command.body {
+                // The flow didn't start the authentication, so it can't be forwarded
+                warn!(command=%format!("{:?}", command).red(), role = "c2p", "|--> Rejected authenticate with duplicate tag");
+                let status = Status::bad(Some(command.tag), None, DUPLICATE_TAG_TEXT).unwrap();
+                if let Err(error) = client_to_proxy.enqueue_status(status) {
+                    error!(role = "p2c", %error, "Failed to forward response");
+                    return ControlFlow::Abort;
+                }
+            } else {
+                // Forward it anyway, the server decides how to handle the duplicate tag
+                warn!(command=%format!("{:?}", command).red(), role = "c2p", "|--> Received command with duplicate tag");
+                if let Err(error) = proxy_to_server.enqueue_command(command) {
+                    error!(role = "p2s", %error, "Failed to forward command");
+                    return ControlFlow::Abort;
+                }
+            }
+        }
+        ServerFlowEvent::MultiAppendReceived { commands } => {
+            // Not advertised by the proxy, and the messages can't be forwarded as separate
+            // APPENDs because they must be appended atomically
+            warn!(commands=%format!("{:?}", commands).red(), role = "c2p", "|--> Rejected multiappend");
+            let status =
+                Status::no(Some(commands[0].tag.clone()), None, MULTIAPPEND_REJECT_TEXT).unwrap();
+            if let Err(error) = client_to_proxy.enqueue_status(status) {
+                error!(role = "p2c", %error, "Failed to forward response");
+                return ControlFlow::Abort;
+            }
         }
         ServerFlowEvent::CommandAuthenticateReceived {
             command_authenticate,
@@ -288,8 +345,10 @@ fn handle_client_event(
             let command = command_authenticate.into();
 
             trace!(command=%format!("{:?}", command).red(), role = "c2p", "|--> Received command (authenticate)");
-            let _handle = proxy_to_server.enqueue_command(command);
-            // TODO: log handle
+            if let Err(error) = proxy_to_server.enqueue_command(command) {
+                error!(role = "p2s", %error, "Failed to forward command");
+                return ControlFlow::Abort;
+            }
         }
         ServerFlowEvent::AuthenticateDataReceived { authenticate_data } => {
             trace!(authenticate_data=%format!("{:?}", authenticate_data).red(), role = "c2p", "|--> Received authenticate_data");
@@ -299,6 +358,59 @@ fn handle_client_event(
                 .unwrap();
             // TODO: log handle
         }
+        ServerFlowEvent::ResponseUnsent {
+            handle: _handle,
+            response,
+        } => {
+            // TODO: log handle
+            trace!(role = "p2c", ?response, "<--- Dropped response");
+        }
+        event @ (ServerFlowEvent::RawResponseSent { .. }
+        | ServerFlowEvent::RawResponseUnsent { .. }
+        | ServerFlowEvent::StreamedResponseSent { .. }
+        | ServerFlowEvent::StreamedResponseUnsent { .. }) => {
+            // Not emitted because the proxy doesn't enqueue pre-encoded responses
+            error!(
+                role = "p2c",
+                ?event,
+                "Unexpected pre-encoded response event"
+            );
+        }
+        ServerFlowEvent::MalformedMessageSkipped { discarded_bytes } => {
+            error!(
+                role = "c2p",
+                ?discarded_bytes,
+                "Skipped malformed client message"
+            );
+        }
+        ServerFlowEvent::LiteralAnnounced {
+            tag,
+            length,
+            mode,
+            binary,
+            index,
+        } => {
+            // Not emitted because `ServerFlowOptions::announce_literals` is disabled
+            error!(
+                role = "c2p",
+                ?tag,
+                length,
+                ?mode,
+                binary,
+                index,
+                "Unexpected literal announcement"
+            );
+        }
+        ServerFlowEvent::LiteralProgress {
+            announced,
+            received,
+        } => {
+            // Not emitted because `ServerFlowOptions::literal_progress_granularity` is disabled
+            error!(
+                role = "c2p",
+                announced, received, "Unexpected literal progress"
+            );
+        }
     }
 
     ControlFlow::Continue
@@ -325,6 +437,14 @@ fn handle_server_event(
             error!(role = "s2p", %error, "Connection terminated");
             return ControlFlow::Abort;
         }
+        Err(
+            error @ (ClientFlowError::GreetingBye { .. }
+            | ClientFlowError::LiteralReader(_)
+            | ClientFlowError::Closed),
+        ) => {
+            error!(role = "s2p", %error, "Connection closed");
+            return ControlFlow::Abort;
+        }
     };
 
     match event {
@@ -339,6 +459,7 @@ fn handle_server_event(
             handle: _handle,
             command,
             status,
+            ..
         } => {
             // TODO: log handle
             trace!(role = "p2s", ?command, ?status, "---> Aborted command");
@@ -357,8 +478,10 @@ fn handle_server_event(
                     Status::bad(Some(command.tag), None, COMMAND_REJECTED_TEXT).unwrap()
                 }
             };
-            let _handle = client_to_proxy.enqueue_status(status);
-            // TODO: log handle
+            if let Err(error) = client_to_proxy.enqueue_status(status) {
+                error!(role = "p2c", %error, "Failed to forward response");
+                return ControlFlow::Abort;
+            }
         }
         ClientFlowEvent::AuthenticateStarted { handle: _handle } => {
             // TODO: log handle
@@ -378,23 +501,68 @@ fn handle_server_event(
             // TODO: Fix unwrap
             client_to_proxy.authenticate_finish(status).unwrap();
         }
+        ClientFlowEvent::DataAvailable => {
+            // Not emitted because `ClientFlowOptions::borrow_data` is disabled
+            error!(role = "s2p", "Unexpected borrowed data");
+        }
+        ClientFlowEvent::LiteralProgress {
+            announced,
+            received,
+        } => {
+            // Not emitted because `ClientFlowOptions::literal_progress_granularity` is disabled
+            error!(
+                role = "s2p",
+                announced, received, "Unexpected literal progress"
+            );
+        }
+        event @ (ClientFlowEvent::AppendProgress { .. }
+        | ClientFlowEvent::AppendSent { .. }
+        | ClientFlowEvent::AppendRejected { .. }
+        | ClientFlowEvent::AppendUnsent { .. }) => {
+            // Not emitted because the proxy forwards `APPEND` via `ClientFlow::enqueue_command`
+            error!(role = "p2s", ?event, "Unexpected append event");
+        }
         ClientFlowEvent::DataReceived { mut data } => {
             trace!(data=%format!("{:?}", data).blue(), role = "s2p", "<--| Received data");
             util::filter_capabilities_in_data(&mut data);
-            let _handle = client_to_proxy.enqueue_data(data);
-            // TODO: log handle
+            if let Err(error) = client_to_proxy.enqueue_data(data) {
+                error!(role = "p2c", %error, "Failed to forward response");
+                return ControlFlow::Abort;
+            }
+        }
+        ClientFlowEvent::ESearchReceived { esearch } => {
+            // Not emitted because the proxy doesn't advertise `IMAP4rev2`
+            error!(role = "s2p", ?esearch, "Unexpected ESEARCH");
         }
         ClientFlowEvent::StatusReceived { mut status } => {
             trace!(response=%format!("{:?}", status).blue(), role = "s2p", "<--| Received status");
             util::filter_capabilities_in_status(&mut status);
-            let _handle = client_to_proxy.enqueue_status(status);
-            // TODO: log handle
+            if let Err(error) = client_to_proxy.enqueue_status(status) {
+                error!(role = "p2c", %error, "Failed to forward response");
+                return ControlFlow::Abort;
+            }
         }
         ClientFlowEvent::ContinuationReceived { mut continuation } => {
             trace!(response=%format!("{:?}", continuation).blue(), role = "s2p", "<--| Received continuation");
             util::filter_capabilities_in_continuation(&mut continuation);
-            let _handle = client_to_proxy.enqueue_continuation(continuation);
+            if let Err(error) = client_to_proxy.enqueue_continuation(continuation) {
+                error!(role = "p2c", %error, "Failed to forward response");
+                return ControlFlow::Abort;
+            }
+        }
+        ClientFlowEvent::CommandUnsent {
+            handle: _handle,
+            command,
+        } => {
             // TODO: log handle
+            trace!(role = "p2s", ?command, "---> Dropped command");
+        }
+        ClientFlowEvent::MalformedMessageSkipped { discarded_bytes } => {
+            error!(
+                role = "s2p",
+                ?discarded_bytes,
+                "Skipped malformed server message"
+            );
         }
     }
 
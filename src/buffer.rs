@@ -0,0 +1,35 @@
+//! Bounds the memory held by the read and write buffers of idle flows.
+
+use bytes::BytesMut;
+
+/// Maximum capacity that is retained after a message was processed completely.
+///
+/// Larger allocations, e.g., from receiving or sending a big literal, are released so that idle
+/// connections don't keep them alive.
+pub(crate) const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// Maximum number of bytes reserved at once while receiving a literal.
+///
+/// The announced literal length is not trusted, the read buffer only grows with the bytes that
+/// were actually received.
+pub(crate) const LITERAL_RESERVATION_STEP: usize = 64 * 1024;
+
+/// Maximum number of bytes of a [`LiteralReader`] that are buffered at once while sending.
+///
+/// [`LiteralReader`]: crate::types::LiteralReader
+pub(crate) const LITERAL_READER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Replaces the allocation of the buffer with a fitting one if it exceeds
+/// [`MAX_RETAINED_CAPACITY`].
+///
+/// `consumed` is the number of bytes that were just removed from the front of the buffer. They
+/// still occupy the allocation but are no longer reported by [`BytesMut::capacity`].
+///
+/// Note: The remaining bytes are kept. They are only copied if there are at most
+/// [`MAX_RETAINED_CAPACITY`] of them, otherwise the allocation is still in use anyway.
+pub(crate) fn shrink(buffer: &mut BytesMut, consumed: usize) {
+    if consumed + buffer.capacity() > MAX_RETAINED_CAPACITY && buffer.len() <= MAX_RETAINED_CAPACITY
+    {
+        *buffer = BytesMut::from(&buffer[..]);
+    }
+}
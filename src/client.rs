@@ -1,14 +1,16 @@
-use std::fmt::Debug;
+use std::{collections::VecDeque, fmt::Debug};
 
-use bytes::BytesMut;
+use bounded_static::{IntoBoundedStatic, ToBoundedStatic};
+use bytes::{Bytes, BytesMut};
 use imap_codec::{
-    decode::{GreetingDecodeError, ResponseDecodeError},
+    decode::{Decoder, GreetingDecodeError, ResponseDecodeError},
     imap_types::{
         auth::AuthenticateData,
-        command::Command,
+        command::{Command, CommandBody},
+        core::{Tag, Text},
         response::{
-            CommandContinuationRequest, Data, Greeting, Response, Status, StatusBody, StatusKind,
-            Tagged,
+            Code, CommandContinuationRequest, Data, Greeting, GreetingKind, Response, Status,
+            StatusBody, StatusKind, Tagged,
         },
     },
     AuthenticateDataCodec, CommandCodec, GreetingCodec, ResponseCodec,
@@ -16,19 +18,40 @@ use imap_codec::{
 use thiserror::Error;
 
 use crate::{
+    esearch,
     handle::{Handle, HandleGenerator, HandleGeneratorGenerator, RawHandle},
-    receive::{ReceiveEvent, ReceiveState},
-    send::{SendCommandEvent, SendCommandKind, SendCommandState},
+    receive::{BorrowFilter, ReceiveEvent, ReceiveState},
+    send::{SendCommandEvent, SendCommandKind, SendCommandState, SendError},
     stream::{AnyStream, StreamError},
-    types::CommandAuthenticate,
+    types::{
+        CommandAppend, CommandAuthenticate, ESearchResponse, EnabledExtensions, ProtocolVersion,
+    },
 };
 
-static HANDLE_GENERATOR_GENERATOR: HandleGeneratorGenerator<ClientFlowCommandHandle> =
+pub(crate) static HANDLE_GENERATOR_GENERATOR: HandleGeneratorGenerator<ClientFlowCommandHandle> =
     HandleGeneratorGenerator::new();
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ClientFlowOptions {
     pub crlf_relaxed: bool,
+    /// Skip malformed responses instead of failing with [`ClientFlowError::MalformedMessage`].
+    ///
+    /// Literals announced by a malformed response are skipped, too. This keeps the flow usable
+    /// when talking to servers that send non-RFC responses. Skipped responses are reported via
+    /// [`ClientFlowEvent::MalformedMessageSkipped`].
+    pub skip_malformed_messages: bool,
+    /// Don't copy [`Data`] responses out of the read buffer.
+    ///
+    /// Instead of [`ClientFlowEvent::DataReceived`], [`ClientFlowEvent::DataAvailable`] is
+    /// returned and the response can be borrowed via [`ClientFlow::data`] until the next call of
+    /// [`ClientFlow::progress`]. This avoids copying large message bodies, e.g., from `FETCH`.
+    pub borrow_data: bool,
+    /// Report the progress of literals received from the server.
+    ///
+    /// When set, [`ClientFlowEvent::LiteralProgress`] is returned whenever at least this many
+    /// bytes of a literal were received since the last event. This allows showing a progress bar
+    /// while downloading a large message. `None` disables the events.
+    pub literal_progress_granularity: Option<u32>,
 }
 
 impl Default for ClientFlowOptions {
@@ -36,6 +59,12 @@ impl Default for ClientFlowOptions {
         Self {
             // Lean towards usability
             crlf_relaxed: true,
+            // Don't hide protocol violations by default
+            skip_malformed_messages: false,
+            // Owned events are easier to use
+            borrow_data: false,
+            // Don't bother the application by default
+            literal_progress_granularity: None,
         }
     }
 }
@@ -43,17 +72,33 @@ impl Default for ClientFlowOptions {
 #[derive(Debug)]
 pub struct ClientFlow {
     stream: AnyStream,
+    options: ClientFlowOptions,
+    // Options that will be applied at the next message boundary.
+    pending_options: Option<ClientFlowOptions>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 
     handle_generator: HandleGenerator<ClientFlowCommandHandle>,
     send_command_state: SendCommandState<ClientFlowCommandHandle>,
     receive_response_state: ReceiveState<ResponseCodec>,
+
+    state: ClientFlowState,
+    enabled: EnabledExtensions,
+    // Message counters, the remaining statistics are collected on demand.
+    statistics: ClientFlowStatistics,
+    // The `Data` response announced by `ClientFlowEvent::DataAvailable`.
+    data: Option<BorrowedData>,
+    // Tags of sent `LOGIN` commands that were not completed yet.
+    login_tags: Vec<Tag<'static>>,
+    // Commands that will never be sent because the flow reached a terminal state.
+    unsent_commands: VecDeque<(ClientFlowCommandHandle, SendCommandKind)>,
 }
 
 impl ClientFlow {
     pub async fn receive_greeting(
         mut stream: AnyStream,
         options: ClientFlowOptions,
-    ) -> Result<(Self, Greeting<'static>), ClientFlowError> {
+    ) -> Result<(Self, ClientFlowGreeting), ClientFlowError> {
         // Receive greeting.
         let mut receive_greeting_state = ReceiveState::new(
             GreetingCodec::default(),
@@ -61,20 +106,64 @@ impl ClientFlow {
             BytesMut::new(),
         );
 
-        let greeting = match receive_greeting_state.progress(&mut stream).await? {
-            ReceiveEvent::DecodingSuccess(greeting) => {
-                receive_greeting_state.finish_message();
-                greeting
-            }
-            ReceiveEvent::DecodingFailure(
-                GreetingDecodeError::Failed | GreetingDecodeError::Incomplete,
-            ) => {
-                let discarded_bytes = receive_greeting_state.discard_message();
-                return Err(ClientFlowError::MalformedMessage { discarded_bytes });
+        let greeting = loop {
+            match receive_greeting_state.progress(&mut stream).await? {
+                ReceiveEvent::DecodingSuccess(greeting) => {
+                    receive_greeting_state.finish_message();
+                    break greeting;
+                }
+                ReceiveEvent::DecodingSuccessBorrowed(bytes) => {
+                    // No borrow filter is set for the greeting, but the message is still valid.
+                    match receive_greeting_state.decode_static(&bytes) {
+                        Some(greeting) => break greeting,
+                        None => {
+                            return Err(ClientFlowError::MalformedMessage {
+                                discarded_bytes: Box::from(&bytes[..]),
+                            });
+                        }
+                    }
+                }
+                ReceiveEvent::DecodingFailure(
+                    GreetingDecodeError::Failed | GreetingDecodeError::Incomplete,
+                )
+                | ReceiveEvent::Unexpected8Bit
+                | ReceiveEvent::MessageSkipped => {
+                    // We never skip a greeting because the flow can't be used without it.
+                    let discarded_bytes = receive_greeting_state.discard_message();
+                    return Err(ClientFlowError::MalformedMessage { discarded_bytes });
+                }
+                ReceiveEvent::ExpectedCrlfGotLf => {
+                    let discarded_bytes = receive_greeting_state.discard_message();
+                    return Err(ClientFlowError::ExpectedCrlfGotLf { discarded_bytes });
+                }
+                ReceiveEvent::LiteralProgress { .. } => {
+                    // The progress of literals is only reported after the greeting.
+                    continue;
+                }
             }
-            ReceiveEvent::ExpectedCrlfGotLf => {
-                let discarded_bytes = receive_greeting_state.discard_message();
-                return Err(ClientFlowError::ExpectedCrlfGotLf { discarded_bytes });
+        };
+
+        let (greeting, state) = match greeting.kind {
+            GreetingKind::Ok => (
+                ClientFlowGreeting::Ok {
+                    code: greeting.code,
+                    text: greeting.text,
+                },
+                ClientFlowState::NotAuthenticated,
+            ),
+            GreetingKind::PreAuth => (
+                ClientFlowGreeting::PreAuth {
+                    code: greeting.code,
+                    text: greeting.text,
+                },
+                ClientFlowState::Authenticated,
+            ),
+            GreetingKind::Bye => {
+                // The server is going to close the connection, there is no point in creating a flow.
+                return Err(ClientFlowError::GreetingBye {
+                    code: greeting.code,
+                    text: greeting.text,
+                });
             }
         };
 
@@ -86,30 +175,252 @@ impl ClientFlow {
         );
 
         // ..., and state to receive responses.
-        let receive_response_state = receive_greeting_state.change_codec(ResponseCodec::new());
+        let mut receive_response_state = receive_greeting_state.change_codec(ResponseCodec::new());
+        receive_response_state.set_borrow_filter(borrow_filter(&options));
+        receive_response_state
+            .set_literal_progress_granularity(options.literal_progress_granularity);
+
+        let handle_generator = HANDLE_GENERATOR_GENERATOR.generate();
 
         let client_flow = Self {
             stream,
-            handle_generator: HANDLE_GENERATOR_GENERATOR.generate(),
+            options,
+            pending_options: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("client_flow", flow_id = handle_generator.generator_id()),
+            handle_generator,
             send_command_state,
             receive_response_state,
+            state,
+            enabled: EnabledExtensions::default(),
+            statistics: ClientFlowStatistics::default(),
+            data: None,
+            login_tags: Vec::new(),
+            unsent_commands: VecDeque::new(),
         };
 
         Ok((client_flow, greeting))
     }
 
+    /// Returns the current [`ClientFlowState`].
+    pub fn state(&self) -> ClientFlowState {
+        self.state
+    }
+
+    /// Returns a snapshot of the [`ClientFlowStatistics`].
+    pub fn statistics(&self) -> ClientFlowStatistics {
+        ClientFlowStatistics {
+            bytes_read: self.stream.bytes_read(),
+            bytes_written: self.stream.bytes_written(),
+            discarded_messages: self.receive_response_state.discarded_messages(),
+            send_queue_len: self.send_command_state.len(),
+            read_buffer_len: self.receive_response_state.buffered_bytes(),
+            read_buffer_capacity: self.receive_response_state.buffer_capacity(),
+            ..self.statistics
+        }
+    }
+
+    /// Returns the extensions enabled via `ENABLE`.
+    ///
+    /// The flow observes `ENABLED` responses from the server. Before `UTF8=ACCEPT` or
+    /// `IMAP4rev2` is enabled, responses with 8-bit characters outside of literals are handled
+    /// like malformed responses. Afterwards, they are left to the decoder from the next response on.
+    pub fn enabled(&self) -> EnabledExtensions {
+        self.enabled
+    }
+
+    /// Returns the [`ProtocolVersion`] negotiated via `ENABLE IMAP4rev2`.
+    ///
+    /// Use [`ClientFlow::set_enabled`] when talking to an IMAP4rev2-only server.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.enabled.protocol_version()
+    }
+
+    /// Overrides the extensions enabled via `ENABLE`.
+    ///
+    /// This is useful when the extensions were enabled without the flow observing it.
+    pub fn set_enabled(&mut self, enabled: EnabledExtensions) {
+        self.enabled = enabled;
+    }
+
+    /// Returns the [`ClientFlowOptions`] currently in effect.
+    pub fn options(&self) -> &ClientFlowOptions {
+        &self.options
+    }
+
+    /// Changes the [`ClientFlowOptions`].
+    ///
+    /// The options take effect at the next message boundary. If a response is currently being
+    /// received, e.g., while waiting for a literal, it is still processed with the old options.
+    pub fn set_options(&mut self, options: ClientFlowOptions) {
+        self.pending_options = Some(options);
+        self.apply_pending_options();
+    }
+
+    fn apply_pending_options(&mut self) {
+        if !self.receive_response_state.is_message_boundary() {
+            return;
+        }
+
+        // UTF-8 is accepted from the response after `ENABLED` on.
+        let utf8_accepted =
+            self.enabled.utf8_accept || self.protocol_version() == ProtocolVersion::Imap4rev2;
+        self.receive_response_state.set_utf8_accepted(utf8_accepted);
+
+        if let Some(options) = self.pending_options.take() {
+            self.receive_response_state
+                .set_crlf_relaxed(options.crlf_relaxed);
+            self.receive_response_state
+                .set_borrow_filter(borrow_filter(&options));
+            self.receive_response_state
+                .set_literal_progress_granularity(options.literal_progress_granularity);
+            self.options = options;
+        }
+    }
+
+    /// Returns the [`Data`] response announced by [`ClientFlowEvent::DataAvailable`].
+    ///
+    /// The response borrows from the received bytes, so it is never copied. It is decoded twice,
+    /// though, because the borrow filter's decoding result can't borrow from the read buffer
+    /// after the bytes were split off. It stays available until the next call of
+    /// [`ClientFlow::progress`]. Returns `None` if there is no such response.
+    pub fn data(&self) -> Option<&Data<'_>> {
+        self.data.as_ref().map(BorrowedData::borrow_dependent)
+    }
+
+    /// Converts [`ClientFlowEvent::DataAvailable`] into [`ClientFlowEvent::DataReceived`] by
+    /// copying the borrowed [`Data`] response. All other events are returned unchanged.
+    ///
+    /// This is useful when the event needs to outlive the next call of [`ClientFlow::progress`].
+    pub fn to_owned_event(&self, event: ClientFlowEvent) -> ClientFlowEvent {
+        match event {
+            ClientFlowEvent::DataAvailable => match self.data() {
+                Some(data) => ClientFlowEvent::DataReceived {
+                    data: data.to_static(),
+                },
+                None => event,
+            },
+            event => event,
+        }
+    }
+
+    // Counts `Data` responses that are not part of the protocol version.
+    fn check_data(&mut self, data: &Data) {
+        // `RECENT` was removed in IMAP4rev2, see RFC 9051, appendix E.
+        if matches!(data, Data::Recent(_)) && self.protocol_version() == ProtocolVersion::Imap4rev2
+        {
+            warn!("Received RECENT although IMAP4rev2 is used");
+            self.statistics.unexpected_responses += 1;
+        }
+    }
+
+    // Drops the `Data` response that was kept for `ClientFlow::data`.
+    fn release_data(&mut self) {
+        self.data = None;
+    }
+
     /// Enqueues the [`Command`] for being sent to the client.
     ///
     /// The [`Command`] is not sent immediately but during one of the next calls of
     /// [`ClientFlow::progress`]. All [`Command`]s are sent in the same order they have been
     /// enqueued.
-    pub fn enqueue_command(&mut self, command: Command<'static>) -> ClientFlowCommandHandle {
+    ///
+    /// Returns [`ClientFlowEnqueueError`] if the flow is in [`ClientFlowState::Logout`] or
+    /// [`ClientFlowState::Closed`].
+    pub fn enqueue_command(
+        &mut self,
+        command: Command<'static>,
+    ) -> Result<ClientFlowCommandHandle, ClientFlowEnqueueError> {
         let handle = self.handle_generator.generate();
+        self.enqueue_command_with_handle(handle, command)?;
+        Ok(handle)
+    }
+
+    // Enqueues a command whose handle was generated elsewhere, e.g., by a `ClientFlowSink`.
+    pub(crate) fn enqueue_command_with_handle(
+        &mut self,
+        handle: ClientFlowCommandHandle,
+        command: Command<'static>,
+    ) -> Result<(), ClientFlowEnqueueError> {
+        if let ClientFlowState::Logout | ClientFlowState::Closed = self.state {
+            return Err(ClientFlowEnqueueError { command });
+        }
+
         self.send_command_state.enqueue(handle, command);
-        handle
+        Ok(())
     }
 
+    /// Enqueues the [`CommandAppend`] for being sent to the server.
+    ///
+    /// Unlike an `APPEND` enqueued via [`ClientFlow::enqueue_command`], the messages are not
+    /// loaded into memory. Each message is read from its [`LiteralReader`] in chunks after the
+    /// server accepted the literal. [`ClientFlowEvent::AppendProgress`] is returned after every
+    /// chunk and [`ClientFlowEvent::AppendSent`] after the command was sent completely.
+    ///
+    /// Note: If a reader fails or ends early, [`ClientFlowError::LiteralReader`] is returned and
+    /// the flow is closed because the command can't be completed.
+    ///
+    /// Returns [`ClientFlowEnqueueAppendError`] if the flow is in [`ClientFlowState::Logout`] or
+    /// [`ClientFlowState::Closed`], or if the [`CommandAppend`] can't be encoded.
+    ///
+    /// [`LiteralReader`]: crate::types::LiteralReader
+    pub fn enqueue_append(
+        &mut self,
+        append: CommandAppend,
+    ) -> Result<ClientFlowCommandHandle, ClientFlowEnqueueAppendError> {
+        if let ClientFlowState::Logout | ClientFlowState::Closed = self.state {
+            return Err(ClientFlowEnqueueAppendError::Closed { append });
+        }
+
+        let handle = self.handle_generator.generate();
+        match self.send_command_state.enqueue_append(handle, append) {
+            Ok(()) => Ok(handle),
+            Err(append) => Err(ClientFlowEnqueueAppendError::Encoding { append }),
+        }
+    }
+
+    /// Progresses the flow and returns the next event.
+    ///
+    /// After the flow reached [`ClientFlowState::Logout`] or [`ClientFlowState::Closed`], all
+    /// commands that were not sent completely are returned via [`ClientFlowEvent::CommandUnsent`]
+    /// first. In [`ClientFlowState::Logout`] the flow continues to receive responses until the
+    /// server closes the connection. In [`ClientFlowState::Closed`]
+    /// [`ClientFlowError::Closed`] is returned.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the returned future is dropped before completion, e.g.,
+    /// because another branch of `tokio::select!` completed first, no bytes are lost or
+    /// duplicated. Partially sent commands and partially received responses are kept in the flow
+    /// and progressed by the next call.
     pub async fn progress(&mut self) -> Result<ClientFlowEvent, ClientFlowError> {
+        instrument!(self.progress_instrumented(), self.span).await
+    }
+
+    async fn progress_instrumented(&mut self) -> Result<ClientFlowEvent, ClientFlowError> {
+        self.release_data();
+
+        if let Some((handle, kind)) = self.unsent_commands.pop_front() {
+            return Ok(unsent_event(handle, kind));
+        }
+
+        let result = match self.state {
+            ClientFlowState::NotAuthenticated | ClientFlowState::Authenticated => {
+                self.progress_open().await
+            }
+            ClientFlowState::Logout => self.progress_logout().await,
+            ClientFlowState::Closed => return Err(ClientFlowError::Closed),
+        };
+
+        if let Err(ClientFlowError::Stream(_) | ClientFlowError::LiteralReader(_)) = result {
+            // We can't rely on the stream anymore.
+            self.terminate(ClientFlowState::Closed);
+        }
+
+        result
+    }
+
+    async fn progress_open(&mut self) -> Result<ClientFlowEvent, ClientFlowError> {
         // The client must do two things:
         // - Sending commands to the server.
         // - Receiving responses from the server.
@@ -140,21 +451,64 @@ impl ClientFlow {
         }
     }
 
+    async fn progress_logout(&mut self) -> Result<ClientFlowEvent, ClientFlowError> {
+        // The server is going to close the connection. We don't send commands anymore but we
+        // still receive the remaining responses, e.g., the tagged status of `LOGOUT`.
+        loop {
+            if let Some(event) = self.progress_receive().await? {
+                return Ok(event);
+            }
+        }
+    }
+
+    // Enters a terminal state and moves all commands that were not sent completely to
+    // `unsent_commands`.
+    fn terminate(&mut self, state: ClientFlowState) {
+        self.state = state;
+        self.unsent_commands.extend(self.send_command_state.drain());
+    }
+
     async fn progress_send(&mut self) -> Result<Option<ClientFlowEvent>, ClientFlowError> {
         match self.send_command_state.progress(&mut self.stream).await? {
             Some(SendCommandEvent::CommandSent {
                 key: handle,
                 command,
-            }) => Ok(Some(ClientFlowEvent::CommandSent { handle, command })),
+            }) => {
+                if let CommandBody::Login { .. } = command.body {
+                    // Remember the tag for tracking the outcome of the `LOGIN`.
+                    self.login_tags.push(command.tag.clone());
+                }
+
+                self.statistics.commands_sent += 1;
+                Ok(Some(ClientFlowEvent::CommandSent { handle, command }))
+            }
             Some(SendCommandEvent::CommandAuthenticateStarted { key: handle }) => {
+                self.statistics.commands_sent += 1;
                 Ok(Some(ClientFlowEvent::AuthenticateStarted { handle }))
             }
+            Some(SendCommandEvent::AppendSent { key: handle, tag }) => {
+                self.statistics.commands_sent += 1;
+                Ok(Some(ClientFlowEvent::AppendSent { handle, tag }))
+            }
+            Some(SendCommandEvent::LiteralProgress {
+                key: handle,
+                bytes_sent,
+                bytes_total,
+            }) => Ok(Some(ClientFlowEvent::AppendProgress {
+                handle,
+                bytes_sent,
+                bytes_total,
+            })),
             None => Ok(None),
         }
     }
 
     async fn progress_receive(&mut self) -> Result<Option<ClientFlowEvent>, ClientFlowError> {
+        self.release_data();
+
         let event = loop {
+            self.apply_pending_options();
+
             let response = match self
                 .receive_response_state
                 .progress(&mut self.stream)
@@ -164,31 +518,117 @@ impl ClientFlow {
                     self.receive_response_state.finish_message();
                     response
                 }
+                ReceiveEvent::DecodingSuccessBorrowed(bytes) => {
+                    // The response is kept until the next call of `progress`. It is decoded a
+                    // second time to borrow from `bytes`, but cloning `Bytes` doesn't copy it.
+                    match BorrowedData::try_new(bytes.clone(), decode_data) {
+                        Ok(data) => {
+                            self.check_data(data.borrow_dependent());
+                            self.data = Some(data);
+                            self.statistics.data_received += 1;
+                            break Some(ClientFlowEvent::DataAvailable);
+                        }
+                        Err(()) => {
+                            // Not expected, the borrow filter already decoded a `Data` response.
+                            return Err(ClientFlowError::MalformedMessage {
+                                discarded_bytes: Box::from(&bytes[..]),
+                            });
+                        }
+                    }
+                }
+                ReceiveEvent::LiteralProgress {
+                    announced,
+                    received,
+                } => {
+                    break Some(ClientFlowEvent::LiteralProgress {
+                        announced,
+                        received,
+                    });
+                }
                 ReceiveEvent::DecodingFailure(ResponseDecodeError::LiteralFound { length }) => {
+                    debug!(length, "Literal announced");
+
                     // The client must accept the literal in any case.
                     self.receive_response_state.start_literal(length);
+                    self.statistics.literals_received += 1;
                     continue;
                 }
                 ReceiveEvent::DecodingFailure(
                     ResponseDecodeError::Failed | ResponseDecodeError::Incomplete,
-                ) => {
-                    let discarded_bytes = self.receive_response_state.discard_message();
-                    return Err(ClientFlowError::MalformedMessage { discarded_bytes });
+                )
+                | ReceiveEvent::Unexpected8Bit => {
+                    // imap-codec doesn't decode `ESEARCH`, which replaces `SEARCH` in IMAP4rev2.
+                    if self.protocol_version() == ProtocolVersion::Imap4rev2 {
+                        if let Some(esearch) =
+                            esearch::decode(self.receive_response_state.message())
+                        {
+                            self.receive_response_state.finish_message();
+                            break Some(ClientFlowEvent::ESearchReceived { esearch });
+                        }
+                    }
+
+                    if !self.options.skip_malformed_messages {
+                        let discarded_bytes = self.receive_response_state.discard_message();
+                        return Err(ClientFlowError::MalformedMessage { discarded_bytes });
+                    }
+
+                    // The server sends literals without waiting for a continuation request,
+                    // therefore we need to follow synchronizing literals, too.
+                    if self.receive_response_state.skip_message(true) {
+                        let discarded_bytes = self.receive_response_state.discard_message();
+                        break Some(ClientFlowEvent::MalformedMessageSkipped { discarded_bytes });
+                    }
+
+                    // The malformed response announced a literal, skip it.
+                    continue;
                 }
                 ReceiveEvent::ExpectedCrlfGotLf => {
                     let discarded_bytes = self.receive_response_state.discard_message();
                     return Err(ClientFlowError::ExpectedCrlfGotLf { discarded_bytes });
                 }
+                ReceiveEvent::MessageSkipped => {
+                    let discarded_bytes = self.receive_response_state.discard_message();
+                    break Some(ClientFlowEvent::MalformedMessageSkipped { discarded_bytes });
+                }
             };
 
+            match &response {
+                Response::Status(_) => self.statistics.status_received += 1,
+                Response::Data(_) => self.statistics.data_received += 1,
+                Response::CommandContinuationRequest(_) => {
+                    self.statistics.continuations_received += 1
+                }
+            }
+
             match response {
                 Response::Status(status) => {
+                    self.update_state(&status);
+
                     let event = if let Some(finish_result) = self.maybe_finish_command(&status) {
                         match finish_result {
-                            FinishCommandResult::LiteralRejected { handle, command } => {
+                            FinishCommandResult::LiteralRejected {
+                                handle,
+                                command,
+                                literal_index,
+                            } => {
+                                self.statistics.literals_rejected += 1;
                                 ClientFlowEvent::CommandRejected {
                                     handle,
                                     command,
+                                    literal_index,
+                                    status,
+                                }
+                            }
+                            FinishCommandResult::AppendRejected {
+                                handle,
+                                tag,
+                                literal_index,
+                            } => {
+                                self.statistics.literals_rejected += 1;
+                                ClientFlowEvent::AppendRejected {
+                                    handle,
+                                    tag,
+                                    literal_index,
                                     status,
                                 }
                             }
@@ -215,9 +655,20 @@ impl ClientFlow {
 
                     break Some(event);
                 }
-                Response::Data(data) => break Some(ClientFlowEvent::DataReceived { data }),
+                Response::Data(data) => {
+                    if let Data::Enabled { capabilities } = &data {
+                        self.enabled.update(capabilities);
+                    }
+                    self.check_data(&data);
+
+                    break Some(ClientFlowEvent::DataReceived { data });
+                }
                 Response::CommandContinuationRequest(continuation) => {
+                    // Note: There must be no `.await` between recording the continuation and
+                    // returning. Otherwise, the continuation could get lost on cancellation.
                     if self.send_command_state.continue_literal() {
+                        self.statistics.literals_accepted += 1;
+
                         // We received a continuation that was necessary for sending a command.
                         // So we abort receiving responses for now and continue with sending commands.
                         break None;
@@ -236,8 +687,137 @@ impl ClientFlow {
         Ok(event)
     }
 
+    /// Shuts down the flow gracefully.
+    ///
+    /// All enqueued commands are sent before the write half of the stream is shut down. If
+    /// `wait_for_close` is `true`, responses are received until the server closes the stream.
+    /// Returns all events that occurred in the meantime together with the final state and
+    /// statistics of the flow.
+    ///
+    /// Note: An `AUTHENTICATE` in progress and all commands enqueued after it are not sent
+    /// because they require interaction with the flow user. They are returned via
+    /// [`ClientFlowEvent::CommandUnsent`] and [`ClientFlowEvent::AppendUnsent`] like all other
+    /// commands that were not sent completely.
+    ///
+    /// Returns [`ClientFlowShutdownError`] if the shutdown failed. It contains the events that
+    /// occurred before the failure including all commands that were not sent.
+    pub async fn shutdown(
+        mut self,
+        wait_for_close: bool,
+    ) -> Result<ClientFlowShutdown, ClientFlowShutdownError> {
+        let mut events = Vec::new();
+
+        let mut result = self.send_remaining_commands(&mut events).await;
+
+        // Commands that were not sent before the shutdown, including the ones left by an
+        // earlier termination of the flow.
+        let unsent_commands = self.send_command_state.drain();
+        self.unsent_commands.extend(unsent_commands);
+        events.extend(
+            self.unsent_commands
+                .drain(..)
+                .map(|(handle, kind)| unsent_event(handle, kind)),
+        );
+
+        if result.is_ok() {
+            result = self.close_stream(wait_for_close, &mut events).await;
+        }
+
+        if let Err(ClientFlowError::Stream(_) | ClientFlowError::LiteralReader(_)) = result {
+            // We can't rely on the stream anymore.
+            self.state = ClientFlowState::Closed;
+        }
+
+        let shutdown = ClientFlowShutdown {
+            events,
+            state: self.state,
+            statistics: self.statistics(),
+        };
+
+        match result {
+            Ok(()) => Ok(shutdown),
+            Err(error) => Err(ClientFlowShutdownError { error, shutdown }),
+        }
+    }
+
+    async fn send_remaining_commands(
+        &mut self,
+        events: &mut Vec<ClientFlowEvent>,
+    ) -> Result<(), ClientFlowError> {
+        // Send all enqueued commands. Literals need a continuation request from the server,
+        // therefore we need to receive responses, too.
+        while !self.send_command_state.is_empty() {
+            if let Some(SendCommandKind::Authenticate { .. }) =
+                self.send_command_state.command_in_progress()
+            {
+                break;
+            }
+
+            if let Some(event) = self.progress_send().await? {
+                events.push(event);
+            } else if let Some(event) = self.progress_receive().await? {
+                events.push(self.to_owned_event(event));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn close_stream(
+        &mut self,
+        wait_for_close: bool,
+        events: &mut Vec<ClientFlowEvent>,
+    ) -> Result<(), ClientFlowError> {
+        self.stream.shutdown().await?;
+
+        if wait_for_close {
+            loop {
+                match self.progress_receive().await {
+                    Ok(Some(event)) => events.push(self.to_owned_event(event)),
+                    Ok(None) => {}
+                    Err(ClientFlowError::Stream(StreamError::Closed)) => break,
+                    Err(error) => return Err(error),
+                }
+            }
+
+            self.state = ClientFlowState::Closed;
+        }
+
+        Ok(())
+    }
+
+    fn update_state(&mut self, status: &Status) {
+        let (tag, kind) = match status {
+            Status::Tagged(Tagged {
+                tag,
+                body: StatusBody { kind, .. },
+            }) => (tag, kind),
+            Status::Bye(_) => {
+                if self.state != ClientFlowState::Closed {
+                    self.terminate(ClientFlowState::Logout);
+                }
+                return;
+            }
+            Status::Untagged(_) => return,
+        };
+
+        if let Some(index) = self
+            .login_tags
+            .iter()
+            .position(|login_tag| login_tag == tag)
+        {
+            self.login_tags.remove(index);
+
+            if *kind == StatusKind::Ok {
+                self.state = ClientFlowState::Authenticated;
+            }
+        }
+    }
+
     fn maybe_finish_command(&mut self, status: &Status) -> Option<FinishCommandResult> {
         let command_kind = self.send_command_state.command_in_progress()?;
+        // The command is rejected while waiting for the `Continue` of this literal.
+        let literal_index = self.send_command_state.accepted_literals()?;
 
         match command_kind {
             SendCommandKind::Regular { command } => {
@@ -246,14 +826,42 @@ impl ClientFlow {
                         tag,
                         body: StatusBody { kind, .. },
                         ..
-                    }) if *kind == StatusKind::Bad && tag == &command.tag => {
+                    }) if matches!(kind, StatusKind::No | StatusKind::Bad)
+                        && tag == &command.tag =>
+                    {
                         self.send_command_state.remove_command_in_progress()
                     }
                     _ => None,
                 };
 
                 if let Some((handle, SendCommandKind::Regular { command })) = removed_command {
-                    Some(FinishCommandResult::LiteralRejected { handle, command })
+                    Some(FinishCommandResult::LiteralRejected {
+                        handle,
+                        command,
+                        literal_index,
+                    })
+                } else {
+                    None
+                }
+            }
+            SendCommandKind::Append { tag: append_tag } => {
+                let removed_command = match status {
+                    Status::Tagged(Tagged {
+                        tag,
+                        body: StatusBody { kind, .. },
+                        ..
+                    }) if matches!(kind, StatusKind::No | StatusKind::Bad) && tag == append_tag => {
+                        self.send_command_state.remove_command_in_progress()
+                    }
+                    _ => None,
+                };
+
+                if let Some((handle, SendCommandKind::Append { tag })) = removed_command {
+                    Some(FinishCommandResult::AppendRejected {
+                        handle,
+                        tag,
+                        literal_index,
+                    })
                 } else {
                     None
                 }
@@ -286,10 +894,14 @@ impl ClientFlow {
                 )) = removed_command
                 {
                     match status_kind {
-                        StatusKind::Ok => Some(FinishCommandResult::AuthenticationAccepted {
-                            handle,
-                            command_authenticate,
-                        }),
+                        StatusKind::Ok => {
+                            self.state = ClientFlowState::Authenticated;
+
+                            Some(FinishCommandResult::AuthenticationAccepted {
+                                handle,
+                                command_authenticate,
+                            })
+                        }
                         StatusKind::No | StatusKind::Bad => {
                             Some(FinishCommandResult::AuthenticationRejected {
                                 handle,
@@ -314,10 +926,159 @@ impl ClientFlow {
     }
 }
 
+/// Greeting received by [`ClientFlow::receive_greeting`].
+///
+/// Note: A `BYE` greeting is returned as [`ClientFlowError::GreetingBye`] instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientFlowGreeting {
+    /// The server is ready and the client needs to authenticate.
+    Ok {
+        code: Option<Code<'static>>,
+        text: Text<'static>,
+    },
+    /// The server is ready and the client is already authenticated.
+    ///
+    /// Note: The client should skip authentication.
+    PreAuth {
+        code: Option<Code<'static>>,
+        text: Text<'static>,
+    },
+}
+
+impl From<ClientFlowGreeting> for Greeting<'static> {
+    fn from(greeting: ClientFlowGreeting) -> Self {
+        let (kind, code, text) = match greeting {
+            ClientFlowGreeting::Ok { code, text } => (GreetingKind::Ok, code, text),
+            ClientFlowGreeting::PreAuth { code, text } => (GreetingKind::PreAuth, code, text),
+        };
+
+        Greeting { kind, code, text }
+    }
+}
+
+// A `Data` response borrowed from the received bytes.
+self_cell::self_cell!(
+    struct BorrowedData {
+        owner: Bytes,
+        #[covariant]
+        dependent: Data,
+    }
+
+    impl {Debug}
+);
+
+fn decode_data(bytes: &Bytes) -> Result<Data<'_>, ()> {
+    match ResponseCodec::default().decode(&bytes[..]) {
+        Ok((_, Response::Data(data))) => Ok(data),
+        _ => Err(()),
+    }
+}
+
+// Hands out `Data` responses without copying, except `ENABLED` which is observed by the flow.
+fn borrow_filter(options: &ClientFlowOptions) -> Option<BorrowFilter<ResponseCodec>> {
+    fn borrow_data(response: &Response) -> bool {
+        matches!(response, Response::Data(data) if !matches!(data, Data::Enabled { .. }))
+    }
+
+    options
+        .borrow_data
+        .then_some(borrow_data as BorrowFilter<ResponseCodec>)
+}
+
+/// Statistics of a [`ClientFlow`].
+///
+/// Returned by [`ClientFlow::statistics`]. All counters start at zero when the flow is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientFlowStatistics {
+    /// Total number of bytes read from the stream.
+    pub bytes_read: u64,
+    /// Total number of bytes written to the stream.
+    pub bytes_written: u64,
+    /// Number of commands sent completely.
+    pub commands_sent: u64,
+    /// Number of [`Data`] responses received.
+    pub data_received: u64,
+    /// Number of [`Status`] responses received.
+    pub status_received: u64,
+    /// Number of [`CommandContinuationRequest`] responses received.
+    pub continuations_received: u64,
+    /// Number of literals received from the server.
+    pub literals_received: u64,
+    /// Number of command literals accepted by the server.
+    pub literals_accepted: u64,
+    /// Number of command literals rejected by the server.
+    pub literals_rejected: u64,
+    /// Number of malformed responses that were discarded.
+    pub discarded_messages: u64,
+    /// Number of responses that are not part of the [`ProtocolVersion`], e.g., `RECENT` in
+    /// IMAP4rev2. They are returned nevertheless.
+    pub unexpected_responses: u64,
+    /// Number of enqueued commands that were not sent completely yet.
+    pub send_queue_len: usize,
+    /// Number of bytes in the read buffer.
+    pub read_buffer_len: usize,
+    /// Number of bytes the read buffer can hold without allocating.
+    ///
+    /// Large allocations are released after the response was received completely.
+    pub read_buffer_capacity: usize,
+}
+
+/// The IMAP connection state as observed by [`ClientFlow`].
+///
+/// Note: [`ClientFlow`] doesn't track the "selected" state.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ClientFlowState {
+    /// The client needs to authenticate.
+    NotAuthenticated,
+    /// The client is authenticated, either by a `PREAUTH` greeting, a successful `LOGIN`, or a
+    /// successful `AUTHENTICATE`.
+    Authenticated,
+    /// The server sent `BYE` and is going to close the connection.
+    ///
+    /// Note: No commands are sent anymore but the remaining responses are still received.
+    Logout,
+    /// The stream failed or was closed.
+    Closed,
+}
+
+/// Result of [`ClientFlow::shutdown`].
+#[derive(Debug)]
+pub struct ClientFlowShutdown {
+    /// Events that occurred during the shutdown.
+    pub events: Vec<ClientFlowEvent>,
+    /// [`ClientFlowState`] after the shutdown.
+    ///
+    /// [`ClientFlowState::Closed`] if the server closed the stream.
+    pub state: ClientFlowState,
+    /// [`ClientFlowStatistics`] after the shutdown.
+    pub statistics: ClientFlowStatistics,
+}
+
+// Returns the event for a command that will never be sent.
+fn unsent_event(handle: ClientFlowCommandHandle, kind: SendCommandKind) -> ClientFlowEvent {
+    match kind {
+        SendCommandKind::Regular { command } => ClientFlowEvent::CommandUnsent { handle, command },
+        SendCommandKind::Authenticate {
+            command_authenticate,
+            ..
+        } => ClientFlowEvent::CommandUnsent {
+            handle,
+            command: command_authenticate.into(),
+        },
+        SendCommandKind::Append { tag } => ClientFlowEvent::AppendUnsent { handle, tag },
+    }
+}
+
 enum FinishCommandResult {
     LiteralRejected {
         handle: ClientFlowCommandHandle,
         command: Command<'static>,
+        literal_index: usize,
+    },
+    AppendRejected {
+        handle: ClientFlowCommandHandle,
+        tag: Tag<'static>,
+        literal_index: usize,
     },
     AuthenticationAccepted {
         handle: ClientFlowCommandHandle,
@@ -365,12 +1126,17 @@ pub enum ClientFlowEvent {
     },
     /// Enqueued [`Command`] rejected.
     ///
-    /// Note: Emitted when the server rejected a command literal with `BAD`.
+    /// Note: Emitted when the server rejected a command literal with a tagged `NO` or `BAD`, e.g.,
+    /// because the literal is too long.
     CommandRejected {
         /// Handle to the enqueued [`Command`].
         handle: ClientFlowCommandHandle,
         /// Formerly enqueued [`Command`].
         command: Command<'static>,
+        /// Index of the rejected literal within the [`Command`].
+        ///
+        /// All literals before it were accepted by the server.
+        literal_index: usize,
         /// [`Status`] sent by the server in order to reject the [`Command`].
         ///
         /// Note: [`ClientFlow`] already handled this [`Status`] but it might still have
@@ -378,6 +1144,50 @@ pub enum ClientFlowEvent {
         /// (e.g. [`Code::Alert`](imap_codec::imap_types::response::Code::Alert)).
         status: Status<'static>,
     },
+    /// A chunk of an enqueued [`CommandAppend`] was sent.
+    ///
+    /// The bytes are counted over all messages of the [`CommandAppend`].
+    AppendProgress {
+        /// Handle to the enqueued [`CommandAppend`].
+        handle: ClientFlowCommandHandle,
+        /// Number of message bytes sent so far.
+        bytes_sent: u64,
+        /// Sum of the lengths of all messages.
+        bytes_total: u64,
+    },
+    /// Enqueued [`CommandAppend`] successfully sent.
+    AppendSent {
+        /// Handle to the enqueued [`CommandAppend`].
+        handle: ClientFlowCommandHandle,
+        /// Tag of the formerly enqueued [`CommandAppend`].
+        tag: Tag<'static>,
+    },
+    /// Enqueued [`CommandAppend`] rejected before all messages were sent.
+    ///
+    /// Note: Emitted when the server sent a tagged `NO` or `BAD` instead of accepting a literal.
+    AppendRejected {
+        /// Handle to the enqueued [`CommandAppend`].
+        handle: ClientFlowCommandHandle,
+        /// Tag of the formerly enqueued [`CommandAppend`].
+        tag: Tag<'static>,
+        /// Index of the message whose literal was rejected.
+        ///
+        /// All messages before it were sent completely. This allows to retry the remaining
+        /// messages of a `MULTIAPPEND`.
+        literal_index: usize,
+        /// [`Status`] sent by the server in order to reject the [`CommandAppend`].
+        status: Status<'static>,
+    },
+    /// Enqueued [`CommandAppend`] will never be sent.
+    ///
+    /// Note: Emitted instead of [`ClientFlowEvent::CommandUnsent`] for a [`CommandAppend`]. The
+    /// messages can't be returned because the readers might have been consumed partially.
+    AppendUnsent {
+        /// Handle to the enqueued [`CommandAppend`].
+        handle: ClientFlowCommandHandle,
+        /// Tag of the formerly enqueued [`CommandAppend`].
+        tag: Tag<'static>,
+    },
     AuthenticateStarted {
         handle: ClientFlowCommandHandle,
     },
@@ -408,6 +1218,28 @@ pub enum ClientFlowEvent {
     DataReceived {
         data: Data<'static>,
     },
+    /// Server `ESEARCH` response received.
+    ///
+    /// Only returned in IMAP4rev2 mode, see [`ClientFlow::protocol_version`]. imap-codec doesn't
+    /// decode `ESEARCH`, so it is not a [`Data`] response.
+    ESearchReceived {
+        esearch: ESearchResponse,
+    },
+    /// Server [`Data`] received but not copied out of the read buffer.
+    ///
+    /// Only returned if [`ClientFlowOptions::borrow_data`] is enabled. The response can be
+    /// borrowed via [`ClientFlow::data`] until the next call of [`ClientFlow::progress`].
+    DataAvailable,
+    /// Bytes of a literal were received from the server.
+    ///
+    /// Note: Only emitted when [`ClientFlowOptions::literal_progress_granularity`] is set. The
+    /// response containing the literal is returned once it was received completely.
+    LiteralProgress {
+        /// Length of the literal announced by the server.
+        announced: u32,
+        /// Number of bytes of the literal received so far.
+        received: u32,
+    },
     /// Server [`Status`] received.
     StatusReceived {
         status: Status<'static>,
@@ -418,6 +1250,25 @@ pub enum ClientFlowEvent {
     ContinuationReceived {
         continuation: CommandContinuationRequest<'static>,
     },
+    /// Enqueued [`Command`] will never be sent.
+    ///
+    /// Note: Emitted for every [`Command`] that was not sent completely when the flow reached
+    /// [`ClientFlowState::Logout`] or [`ClientFlowState::Closed`]. The [`Command`] can be
+    /// retried on a new connection.
+    CommandUnsent {
+        /// Handle to the enqueued [`Command`].
+        handle: ClientFlowCommandHandle,
+        /// Formerly enqueued [`Command`].
+        command: Command<'static>,
+    },
+    /// Malformed response skipped.
+    ///
+    /// Note: Only emitted when [`ClientFlowOptions::skip_malformed_messages`] is enabled. The
+    /// flow is still usable afterwards.
+    MalformedMessageSkipped {
+        /// Raw bytes of the skipped response (including skipped literals).
+        discarded_bytes: Box<[u8]>,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -428,4 +1279,65 @@ pub enum ClientFlowError {
     ExpectedCrlfGotLf { discarded_bytes: Box<[u8]> },
     #[error("Received malformed message")]
     MalformedMessage { discarded_bytes: Box<[u8]> },
+    /// The server rejected the connection with a `BYE` greeting.
+    #[error("Received BYE greeting")]
+    GreetingBye {
+        code: Option<Code<'static>>,
+        text: Text<'static>,
+    },
+    /// Reading a message of a [`CommandAppend`] failed.
+    ///
+    /// Note: The flow is closed because the command can't be completed.
+    #[error("Failed to read literal")]
+    LiteralReader(#[source] std::io::Error),
+    /// The flow is in [`ClientFlowState::Closed`] and can't be used anymore.
+    #[error("Flow is closed")]
+    Closed,
+}
+
+impl From<SendError> for ClientFlowError {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::Stream(error) => Self::Stream(error),
+            SendError::LiteralReader(error) => Self::LiteralReader(error),
+        }
+    }
+}
+
+/// Error returned by [`ClientFlow::shutdown`].
+#[derive(Debug, Error)]
+#[error("Failed to shut down flow")]
+pub struct ClientFlowShutdownError {
+    /// The error that stopped the shutdown.
+    #[source]
+    pub error: ClientFlowError,
+    /// Events that occurred before the error together with the final state and statistics.
+    ///
+    /// Contains [`ClientFlowEvent::CommandUnsent`] and [`ClientFlowEvent::AppendUnsent`] for all
+    /// commands that were not sent.
+    pub shutdown: ClientFlowShutdown,
+}
+
+/// Error returned by [`ClientFlow::enqueue_command`].
+#[derive(Debug, Error)]
+#[error("Flow doesn't send commands anymore")]
+pub struct ClientFlowEnqueueError {
+    /// The [`Command`] that was not enqueued.
+    pub command: Command<'static>,
+}
+
+/// Error returned by [`ClientFlow::enqueue_append`].
+#[derive(Debug, Error)]
+pub enum ClientFlowEnqueueAppendError {
+    #[error("Flow doesn't send commands anymore")]
+    Closed {
+        /// The [`CommandAppend`] that was not enqueued.
+        append: CommandAppend,
+    },
+    /// The [`CommandAppend`] has no messages or its options can't be encoded.
+    #[error("Failed to encode APPEND")]
+    Encoding {
+        /// The [`CommandAppend`] that was not enqueued.
+        append: CommandAppend,
+    },
 }
@@ -0,0 +1,322 @@
+//! High-level client API driving a [`ClientFlow`] in a background task.
+//!
+//! [`spawn`] moves the flow into a background task and returns a cloneable
+//! [`ClientDriverHandle`] for sending commands as well as [`UnsolicitedResponses`] for receiving
+//! all responses that don't belong to a command.
+
+use imap_codec::imap_types::{
+    command::{Command, CommandBody},
+    core::Tag,
+    response::{Data, Response, Status, Tagged},
+};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    client::{ClientFlow, ClientFlowCommandHandle, ClientFlowError, ClientFlowEvent},
+    types::ESearchResponse,
+};
+
+/// Spawns a background task driving the [`ClientFlow`].
+///
+/// The task ends when the flow is closed or when all [`ClientDriverHandle`]s were dropped and
+/// all commands in flight are completed.
+///
+/// Note: Must be called within a Tokio runtime. `AUTHENTICATE` is not supported by the driver,
+/// authenticate before spawning it.
+pub fn spawn(flow: ClientFlow) -> (ClientDriverHandle, UnsolicitedResponses) {
+    let (request_sender, request_receiver) = mpsc::unbounded_channel();
+    let (unsolicited_sender, unsolicited_receiver) = mpsc::unbounded_channel();
+
+    let driver = ClientDriver {
+        flow,
+        request_receiver,
+        unsolicited_sender,
+        commands_in_flight: Vec::new(),
+    };
+    tokio::spawn(driver.run());
+
+    (
+        ClientDriverHandle { request_sender },
+        UnsolicitedResponses {
+            receiver: unsolicited_receiver,
+        },
+    )
+}
+
+/// A cloneable handle for sending commands via the background task.
+#[derive(Debug, Clone)]
+pub struct ClientDriverHandle {
+    request_sender: mpsc::UnboundedSender<Request>,
+}
+
+impl ClientDriverHandle {
+    /// Sends the [`Command`] and waits for its completion.
+    ///
+    /// Resolves to the tagged [`Status`] and all [`Data`] responses received while the command
+    /// was in flight.
+    pub async fn send(
+        &self,
+        command: Command<'static>,
+    ) -> Result<CommandResponse, ClientDriverError> {
+        if let CommandBody::Authenticate { .. } = command.body {
+            return Err(ClientDriverError::AuthenticateUnsupported { command });
+        }
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.request_sender
+            .send(Request {
+                command,
+                response_sender,
+            })
+            .map_err(|_| ClientDriverError::Stopped)?;
+
+        response_receiver
+            .await
+            .map_err(|_| ClientDriverError::Stopped)?
+    }
+}
+
+/// Responses that don't belong to a command sent via [`ClientDriverHandle::send`].
+///
+/// This includes untagged status responses, `BYE`, and [`Data`] responses received while no
+/// command was in flight.
+#[derive(Debug)]
+pub struct UnsolicitedResponses {
+    receiver: mpsc::UnboundedReceiver<Response<'static>>,
+}
+
+impl UnsolicitedResponses {
+    /// Receives the next unsolicited [`Response`].
+    ///
+    /// Returns `None` when the background task ended.
+    pub async fn recv(&mut self) -> Option<Response<'static>> {
+        self.receiver.recv().await
+    }
+}
+
+/// Result of a [`Command`] sent via [`ClientDriverHandle::send`].
+#[derive(Debug)]
+pub struct CommandResponse {
+    /// Tagged [`Status`] completing the [`Command`].
+    pub status: Status<'static>,
+    /// [`Data`] responses received while the [`Command`] was in flight.
+    pub data: Vec<Data<'static>>,
+    /// `ESEARCH` responses belonging to the [`Command`], see
+    /// [`ClientFlowEvent::ESearchReceived`].
+    pub esearch: Vec<ESearchResponse>,
+}
+
+#[derive(Debug, Error)]
+pub enum ClientDriverError {
+    /// `AUTHENTICATE` requires interaction with the flow and is not supported.
+    #[error("AUTHENTICATE is not supported by the driver")]
+    AuthenticateUnsupported { command: Command<'static> },
+    /// The [`Command`] will never be sent, e.g., because the server sent `BYE`.
+    #[error("Command was not sent")]
+    Unsent { command: Command<'static> },
+    /// The background task ended before the [`Command`] was completed.
+    #[error("Driver stopped")]
+    Stopped,
+}
+
+#[derive(Debug)]
+struct Request {
+    command: Command<'static>,
+    response_sender: oneshot::Sender<Result<CommandResponse, ClientDriverError>>,
+}
+
+#[derive(Debug)]
+struct CommandInFlight {
+    handle: ClientFlowCommandHandle,
+    tag: Tag<'static>,
+    // Was the command sent completely?
+    sent: bool,
+    data: Vec<Data<'static>>,
+    esearch: Vec<ESearchResponse>,
+    response_sender: oneshot::Sender<Result<CommandResponse, ClientDriverError>>,
+}
+
+#[derive(Debug)]
+struct ClientDriver {
+    flow: ClientFlow,
+    request_receiver: mpsc::UnboundedReceiver<Request>,
+    unsolicited_sender: mpsc::UnboundedSender<Response<'static>>,
+    commands_in_flight: Vec<CommandInFlight>,
+}
+
+impl ClientDriver {
+    async fn run(mut self) {
+        let mut handles_dropped = false;
+
+        while !handles_dropped || !self.commands_in_flight.is_empty() {
+            // Both branches are cancel safe.
+            tokio::select! {
+                request = self.request_receiver.recv(), if !handles_dropped => {
+                    match request {
+                        Some(request) => self.handle_request(request),
+                        None => handles_dropped = true,
+                    }
+                }
+                result = self.flow.progress() => {
+                    match result {
+                        Ok(event) => {
+                            // Data is forwarded to other tasks, so it must be owned.
+                            let event = self.flow.to_owned_event(event);
+                            self.handle_event(event);
+                        }
+                        Err(
+                            ClientFlowError::Stream(_)
+                            | ClientFlowError::LiteralReader(_)
+                            | ClientFlowError::Closed,
+                        ) => break,
+                        Err(_) => {
+                            // The malformed response was discarded, the flow is still usable.
+                        }
+                    }
+                }
+            }
+        }
+
+        // Pending responses are resolved with `ClientDriverError::Stopped` when dropped.
+    }
+
+    fn handle_request(&mut self, request: Request) {
+        let tag = request.command.tag.clone();
+
+        match self.flow.enqueue_command(request.command) {
+            Ok(handle) => self.commands_in_flight.push(CommandInFlight {
+                handle,
+                tag,
+                sent: false,
+                data: Vec::new(),
+                esearch: Vec::new(),
+                response_sender: request.response_sender,
+            }),
+            Err(error) => {
+                let _ = request.response_sender.send(Err(ClientDriverError::Unsent {
+                    command: error.command,
+                }));
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: ClientFlowEvent) {
+        match event {
+            ClientFlowEvent::CommandSent { handle, .. } => {
+                if let Some(command) = self
+                    .commands_in_flight
+                    .iter_mut()
+                    .find(|command| command.handle == handle)
+                {
+                    command.sent = true;
+                }
+            }
+            ClientFlowEvent::CommandRejected { handle, status, .. } => {
+                if let Some(index) = self.find_command(|command| command.handle == handle) {
+                    self.complete(index, status);
+                }
+            }
+            ClientFlowEvent::CommandUnsent { handle, command } => {
+                if let Some(index) = self.find_command(|command| command.handle == handle) {
+                    let command_in_flight = self.commands_in_flight.remove(index);
+                    let _ = command_in_flight
+                        .response_sender
+                        .send(Err(ClientDriverError::Unsent { command }));
+                }
+            }
+            ClientFlowEvent::DataReceived { data } => {
+                let mut sent_commands = self
+                    .commands_in_flight
+                    .iter_mut()
+                    .filter(|command| command.sent)
+                    .peekable();
+
+                if sent_commands.peek().is_none() {
+                    let _ = self.unsolicited_sender.send(Response::Data(data));
+                } else {
+                    for command in sent_commands {
+                        command.data.push(data.clone());
+                    }
+                }
+            }
+            ClientFlowEvent::ESearchReceived { esearch } => {
+                // `ESEARCH` is only sent in response to `SEARCH`, so it's never unsolicited.
+                // Without a tag, it belongs to all commands in flight like other data.
+                for command in self.commands_in_flight.iter_mut().filter(|command| {
+                    command.sent
+                        && (esearch.tag.is_none() || esearch.tag.as_ref() == Some(&command.tag))
+                }) {
+                    command.esearch.push(esearch.clone());
+                }
+            }
+            ClientFlowEvent::StatusReceived { status } => {
+                let index = match &status {
+                    Status::Tagged(Tagged { tag, .. }) => {
+                        self.find_command(|command| command.sent && command.tag == *tag)
+                    }
+                    _ => None,
+                };
+
+                match index {
+                    Some(index) => self.complete(index, status),
+                    None => {
+                        let _ = self.unsolicited_sender.send(Response::Status(status));
+                    }
+                }
+            }
+            ClientFlowEvent::ContinuationReceived { continuation } => {
+                let _ = self
+                    .unsolicited_sender
+                    .send(Response::CommandContinuationRequest(continuation));
+            }
+            ClientFlowEvent::AuthenticateStarted { .. } => {
+                // Not expected, `AUTHENTICATE` is rejected in `ClientDriverHandle::send`.
+            }
+            ClientFlowEvent::ContinuationAuthenticateReceived { continuation, .. } => {
+                // Not expected, but nobody else could handle the continuation request.
+                let _ = self
+                    .unsolicited_sender
+                    .send(Response::CommandContinuationRequest(continuation));
+            }
+            ClientFlowEvent::AuthenticateAccepted { status, .. }
+            | ClientFlowEvent::AuthenticateRejected { status, .. }
+            | ClientFlowEvent::AppendRejected { status, .. } => {
+                // Not expected, the driver only enqueues commands via
+                // `ClientFlow::enqueue_command`. Don't hide the status from the application.
+                let _ = self.unsolicited_sender.send(Response::Status(status));
+            }
+            ClientFlowEvent::MalformedMessageSkipped { .. } => {
+                // Nothing to do, the flow already skipped the malformed response.
+            }
+            ClientFlowEvent::LiteralProgress { .. } => {
+                // Nothing to do, the driver only forwards complete responses.
+            }
+            ClientFlowEvent::DataAvailable => {
+                // Nothing to do, the data was already converted by `ClientFlow::to_owned_event`.
+            }
+            ClientFlowEvent::AppendProgress { .. }
+            | ClientFlowEvent::AppendSent { .. }
+            | ClientFlowEvent::AppendUnsent { .. } => {
+                // Nothing to do, the driver only enqueues commands via
+                // `ClientFlow::enqueue_command`.
+            }
+        }
+    }
+
+    fn find_command<F>(&self, predicate: F) -> Option<usize>
+    where
+        F: Fn(&CommandInFlight) -> bool,
+    {
+        self.commands_in_flight.iter().position(predicate)
+    }
+
+    fn complete(&mut self, index: usize, status: Status<'static>) {
+        let command = self.commands_in_flight.remove(index);
+        let _ = command.response_sender.send(Ok(CommandResponse {
+            status,
+            data: command.data,
+            esearch: command.esearch,
+        }));
+    }
+}
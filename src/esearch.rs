@@ -0,0 +1,79 @@
+//! Decoding of `ESEARCH` responses, see RFC 4731 and RFC 9051, section 7.3.4.
+//!
+//! imap-codec doesn't decode `ESEARCH`, so [`ClientFlow`](crate::client::ClientFlow) decodes it
+//! itself in IMAP4rev2 mode. Responses containing literals are not supported.
+
+use std::{num::NonZeroU32, ops::RangeInclusive};
+
+use imap_codec::imap_types::core::Tag;
+
+use crate::types::ESearchResponse;
+
+/// Decodes an `ESEARCH` response including its line ending.
+///
+/// Returns `None` if the response is not a valid `ESEARCH` response.
+pub(crate) fn decode(response: &[u8]) -> Option<ESearchResponse> {
+    let response = std::str::from_utf8(response).ok()?.strip_suffix('\n')?;
+    let response = response.strip_suffix('\r').unwrap_or(response);
+    let mut rest = strip_prefix_ignore_case(response, "* ESEARCH")?;
+
+    let mut esearch = ESearchResponse::default();
+
+    if let Some(correlator) = rest.strip_prefix(" (") {
+        let (correlator, remaining) = correlator.split_once(')')?;
+        let tag = strip_prefix_ignore_case(correlator, "TAG ")?;
+        let tag = tag.strip_prefix('"')?.strip_suffix('"')?;
+        esearch.tag = Some(Tag::try_from(tag.to_owned()).ok()?);
+        rest = remaining;
+    }
+
+    let mut tokens = rest.split(' ').peekable();
+    // Each item is preceded by a space.
+    if !tokens.next()?.is_empty() {
+        return None;
+    }
+
+    if tokens
+        .next_if(|token| token.eq_ignore_ascii_case("UID"))
+        .is_some()
+    {
+        esearch.uid = true;
+    }
+
+    while let Some(name) = tokens.next() {
+        let value = tokens.next()?;
+
+        match name.to_ascii_uppercase().as_str() {
+            "MIN" => esearch.min = Some(value.parse().ok()?),
+            "MAX" => esearch.max = Some(value.parse().ok()?),
+            "ALL" => esearch.all = Some(decode_sequence_set(value)?),
+            "COUNT" => esearch.count = Some(value.parse().ok()?),
+            // Values of other return data could contain spaces, e.g., `MODSEQ` (RFC 7162).
+            _ if value.starts_with('(') => return None,
+            _ => {}
+        }
+    }
+
+    Some(esearch)
+}
+
+// Decodes a sequence set without `*`, e.g., `1:3,5`.
+fn decode_sequence_set(value: &str) -> Option<Vec<RangeInclusive<NonZeroU32>>> {
+    value
+        .split(',')
+        .map(|range| {
+            let (start, end) = range.split_once(':').unwrap_or((range, range));
+            let start: NonZeroU32 = start.parse().ok()?;
+            let end: NonZeroU32 = end.parse().ok()?;
+
+            // `3:1` is the same as `1:3`.
+            Some(start.min(end)..=start.max(end))
+        })
+        .collect()
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &value[prefix.len()..])
+}
@@ -0,0 +1,412 @@
+//! Adapters turning the flows into [`Stream`]s of events.
+//!
+//! [`ClientFlowStream`] and [`ServerFlowStream`] own the flow and yield the events returned by
+//! `progress`. Messages are enqueued via the companion [`ClientFlowSink`] and [`ServerFlowSink`].
+//! Between two events, the flow can be accessed via `flow_mut`, e.g., to accept a literal.
+//!
+//! Note: The adapters are cancellation safe. A pending `progress` call is kept inside the
+//! adapter between polls, so no partially received message is lost when the stream is polled
+//! from a `select!` that picks another branch.
+
+use std::{
+    fmt::{Debug, Formatter},
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use imap_codec::imap_types::{command::Command, response::Response};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::{
+    client::{
+        ClientFlow, ClientFlowCommandHandle, ClientFlowEnqueueError, ClientFlowError,
+        ClientFlowEvent,
+    },
+    handle::HandleGenerator,
+    server::{
+        ServerFlow, ServerFlowEnqueueError, ServerFlowError, ServerFlowEvent,
+        ServerFlowResponseHandle,
+    },
+};
+
+type ProgressFuture<F, T, R> =
+    Pin<Box<dyn Future<Output = (F, mpsc::UnboundedReceiver<T>, R)> + Send>>;
+
+// Messages are sent to the stream together with the handle returned by the sink.
+type ClientQueueItem = (ClientFlowCommandHandle, Command<'static>);
+
+type ServerQueueItem = (ServerFlowResponseHandle, Response<'static>);
+
+type ClientProgressFuture =
+    ProgressFuture<ClientFlow, ClientQueueItem, Result<ClientFlowEvent, ClientFlowStreamError>>;
+
+type ServerProgressFuture =
+    ProgressFuture<ServerFlow, ServerQueueItem, Result<ServerFlowEvent, ServerFlowStreamError>>;
+
+/// [`Stream`] of [`ClientFlowEvent`]s.
+///
+/// The stream ends after [`ClientFlowError::Closed`] was returned.
+///
+/// Note: [`ClientFlowEvent::DataAvailable`] is never yielded, the borrowed data is copied into
+/// [`ClientFlowEvent::DataReceived`] instead.
+pub struct ClientFlowStream {
+    // The flow is either idle ...
+    idle: Option<(ClientFlow, mpsc::UnboundedReceiver<ClientQueueItem>)>,
+    // ... or progressing.
+    progress: Option<ClientProgressFuture>,
+    terminated: bool,
+}
+
+impl ClientFlowStream {
+    /// Creates a [`ClientFlowStream`] and the [`ClientFlowSink`] for enqueuing commands.
+    pub fn new(flow: ClientFlow) -> (Self, ClientFlowSink) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let stream = Self {
+            idle: Some((flow, receiver)),
+            progress: None,
+            terminated: false,
+        };
+
+        (stream, ClientFlowSink::new(sender))
+    }
+
+    /// Returns the flow if no event is currently being progressed.
+    ///
+    /// The flow is idle after an event was yielded until the stream is polled again. This allows
+    /// reacting to events that require the flow, e.g., via
+    /// [`ClientFlow::authenticate_continue`].
+    pub fn flow_mut(&mut self) -> Option<&mut ClientFlow> {
+        self.idle.as_mut().map(|(flow, _)| flow)
+    }
+
+    /// Returns the flow if no event is currently being progressed.
+    ///
+    /// Note: Commands that were sent to the [`ClientFlowSink`] but not yet enqueued are lost.
+    pub fn into_inner(self) -> Option<ClientFlow> {
+        self.idle.map(|(flow, _)| flow)
+    }
+}
+
+impl Debug for ClientFlowStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientFlowStream")
+            .field("idle", &self.idle)
+            .field("terminated", &self.terminated)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for ClientFlowStream {
+    type Item = Result<ClientFlowEvent, ClientFlowStreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.terminated {
+            return Poll::Ready(None);
+        }
+
+        let progress = this.progress.get_or_insert_with(|| {
+            // This `unwrap` can't fail because the flow is idle when it's not progressing.
+            let (flow, receiver) = this.idle.take().unwrap();
+            Box::pin(progress_client(flow, receiver))
+        });
+
+        let (flow, receiver, result) = ready!(progress.as_mut().poll(cx));
+        this.progress = None;
+        this.idle = Some((flow, receiver));
+
+        if let Err(ClientFlowStreamError::Flow(ClientFlowError::Closed)) = result {
+            this.terminated = true;
+            return Poll::Ready(None);
+        }
+
+        Poll::Ready(Some(result))
+    }
+}
+
+async fn progress_client(
+    mut flow: ClientFlow,
+    mut receiver: mpsc::UnboundedReceiver<ClientQueueItem>,
+) -> (
+    ClientFlow,
+    mpsc::UnboundedReceiver<ClientQueueItem>,
+    Result<ClientFlowEvent, ClientFlowStreamError>,
+) {
+    loop {
+        // Both branches are cancel safe.
+        tokio::select! {
+            Some((handle, command)) = receiver.recv() => {
+                if let Err(error) = flow.enqueue_command_with_handle(handle, command) {
+                    return (flow, receiver, Err(error.into()));
+                }
+            }
+            result = flow.progress() => {
+                // The event outlives the flow's borrow, so borrowed data must be copied.
+                let result = result.map(|event| flow.to_owned_event(event));
+                return (flow, receiver, result.map_err(Into::into));
+            }
+        }
+    }
+}
+
+/// [`Sink`] for enqueuing [`Command`]s into the flow of a [`ClientFlowStream`].
+///
+/// The commands are enqueued while the [`ClientFlowStream`] is polled. Use
+/// [`ClientFlowSink::enqueue_command`] to get the handle that is used in the events of the
+/// command.
+#[derive(Debug)]
+pub struct ClientFlowSink {
+    // Every sink has its own generator, so we don't need to share the one of the flow.
+    handle_generator: HandleGenerator<ClientFlowCommandHandle>,
+    sender: mpsc::UnboundedSender<ClientQueueItem>,
+}
+
+impl ClientFlowSink {
+    fn new(sender: mpsc::UnboundedSender<ClientQueueItem>) -> Self {
+        Self {
+            handle_generator: crate::client::HANDLE_GENERATOR_GENERATOR.generate(),
+            sender,
+        }
+    }
+
+    /// Sends the [`Command`] to the [`ClientFlowStream`] and returns its handle.
+    ///
+    /// The events of the command, e.g., [`ClientFlowEvent::CommandSent`], contain the returned
+    /// handle. If the flow doesn't accept the command, the stream yields
+    /// [`ClientFlowStreamError::Enqueue`] instead.
+    pub fn enqueue_command(
+        &mut self,
+        command: Command<'static>,
+    ) -> Result<ClientFlowCommandHandle, FlowStreamDropped> {
+        let handle = self.handle_generator.generate();
+        self.sender
+            .send((handle, command))
+            .map_err(|_| FlowStreamDropped)?;
+        Ok(handle)
+    }
+}
+
+impl Clone for ClientFlowSink {
+    fn clone(&self) -> Self {
+        Self::new(self.sender.clone())
+    }
+}
+
+impl Sink<Command<'static>> for ClientFlowSink {
+    type Error = FlowStreamDropped;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, command: Command<'static>) -> Result<(), Self::Error> {
+        self.get_mut().enqueue_command(command).map(|_| ())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClientFlowStreamError {
+    #[error(transparent)]
+    Flow(#[from] ClientFlowError),
+    /// A [`Command`] sent to the [`ClientFlowSink`] was not accepted by the flow.
+    #[error(transparent)]
+    Enqueue(#[from] ClientFlowEnqueueError),
+}
+
+/// [`Stream`] of [`ServerFlowEvent`]s.
+///
+/// The stream ends after [`ServerFlowError::Closed`] was returned.
+///
+/// Note: [`ServerFlow::authenticate_continue`], [`ServerFlow::authenticate_finish`],
+/// [`ServerFlow::literal_accept`], and [`ServerFlow::literal_reject`] require access to the flow.
+/// Use [`ServerFlowStream::flow_mut`] after the event that requires them.
+pub struct ServerFlowStream {
+    // The flow is either idle ...
+    idle: Option<(ServerFlow, mpsc::UnboundedReceiver<ServerQueueItem>)>,
+    // ... or progressing.
+    progress: Option<ServerProgressFuture>,
+    terminated: bool,
+}
+
+impl ServerFlowStream {
+    /// Creates a [`ServerFlowStream`] and the [`ServerFlowSink`] for enqueuing responses.
+    pub fn new(flow: ServerFlow) -> (Self, ServerFlowSink) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let stream = Self {
+            idle: Some((flow, receiver)),
+            progress: None,
+            terminated: false,
+        };
+
+        (stream, ServerFlowSink::new(sender))
+    }
+
+    /// Returns the flow if no event is currently being progressed.
+    ///
+    /// The flow is idle after an event was yielded until the stream is polled again. This allows
+    /// reacting to events that require the flow, e.g., via [`ServerFlow::literal_accept`] after
+    /// [`ServerFlowEvent::LiteralAnnounced`].
+    pub fn flow_mut(&mut self) -> Option<&mut ServerFlow> {
+        self.idle.as_mut().map(|(flow, _)| flow)
+    }
+
+    /// Returns the flow if no event is currently being progressed.
+    ///
+    /// Note: Responses that were sent to the [`ServerFlowSink`] but not yet enqueued are lost.
+    pub fn into_inner(self) -> Option<ServerFlow> {
+        self.idle.map(|(flow, _)| flow)
+    }
+}
+
+impl Debug for ServerFlowStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerFlowStream")
+            .field("idle", &self.idle)
+            .field("terminated", &self.terminated)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for ServerFlowStream {
+    type Item = Result<ServerFlowEvent, ServerFlowStreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.terminated {
+            return Poll::Ready(None);
+        }
+
+        let progress = this.progress.get_or_insert_with(|| {
+            // This `unwrap` can't fail because the flow is idle when it's not progressing.
+            let (flow, receiver) = this.idle.take().unwrap();
+            Box::pin(progress_server(flow, receiver))
+        });
+
+        let (flow, receiver, result) = ready!(progress.as_mut().poll(cx));
+        this.progress = None;
+        this.idle = Some((flow, receiver));
+
+        if let Err(ServerFlowStreamError::Flow(ServerFlowError::Closed)) = result {
+            this.terminated = true;
+            return Poll::Ready(None);
+        }
+
+        Poll::Ready(Some(result))
+    }
+}
+
+async fn progress_server(
+    mut flow: ServerFlow,
+    mut receiver: mpsc::UnboundedReceiver<ServerQueueItem>,
+) -> (
+    ServerFlow,
+    mpsc::UnboundedReceiver<ServerQueueItem>,
+    Result<ServerFlowEvent, ServerFlowStreamError>,
+) {
+    loop {
+        // Both branches are cancel safe.
+        tokio::select! {
+            Some((handle, response)) = receiver.recv() => {
+                if let Err(error) = flow.enqueue_response_with_handle(handle, response) {
+                    return (flow, receiver, Err(error.into()));
+                }
+            }
+            result = flow.progress() => {
+                return (flow, receiver, result.map_err(Into::into));
+            }
+        }
+    }
+}
+
+/// [`Sink`] for enqueuing [`Response`]s into the flow of a [`ServerFlowStream`].
+///
+/// The responses are enqueued while the [`ServerFlowStream`] is polled. Use
+/// [`ServerFlowSink::enqueue_response`] to get the handle that is used in the events of the
+/// response.
+#[derive(Debug)]
+pub struct ServerFlowSink {
+    // Every sink has its own generator, so we don't need to share the one of the flow.
+    handle_generator: HandleGenerator<ServerFlowResponseHandle>,
+    sender: mpsc::UnboundedSender<ServerQueueItem>,
+}
+
+impl ServerFlowSink {
+    fn new(sender: mpsc::UnboundedSender<ServerQueueItem>) -> Self {
+        Self {
+            handle_generator: crate::server::HANDLE_GENERATOR_GENERATOR.generate(),
+            sender,
+        }
+    }
+
+    /// Sends the [`Response`] to the [`ServerFlowStream`] and returns its handle.
+    ///
+    /// [`ServerFlowEvent::ResponseSent`] contains the returned handle. If the flow doesn't
+    /// accept the response, the stream yields [`ServerFlowStreamError::Enqueue`] instead.
+    pub fn enqueue_response(
+        &mut self,
+        response: Response<'static>,
+    ) -> Result<ServerFlowResponseHandle, FlowStreamDropped> {
+        let handle = self.handle_generator.generate();
+        self.sender
+            .send((handle, response))
+            .map_err(|_| FlowStreamDropped)?;
+        Ok(handle)
+    }
+}
+
+impl Clone for ServerFlowSink {
+    fn clone(&self) -> Self {
+        Self::new(self.sender.clone())
+    }
+}
+
+impl Sink<Response<'static>> for ServerFlowSink {
+    type Error = FlowStreamDropped;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<'static>) -> Result<(), Self::Error> {
+        self.get_mut().enqueue_response(response).map(|_| ())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ServerFlowStreamError {
+    #[error(transparent)]
+    Flow(#[from] ServerFlowError),
+    /// A [`Response`] sent to the [`ServerFlowSink`] was not accepted by the flow.
+    #[error(transparent)]
+    Enqueue(#[from] ServerFlowEnqueueError),
+}
+
+/// Error returned by [`ClientFlowSink`] and [`ServerFlowSink`] when the corresponding stream was
+/// dropped.
+#[derive(Debug, Error)]
+#[error("Flow stream was dropped")]
+pub struct FlowStreamDropped;
@@ -34,6 +34,12 @@ pub struct HandleGenerator<H: Handle> {
 }
 
 impl<H: Handle> HandleGenerator<H> {
+    // Only used for the spans of the flows.
+    #[cfg(feature = "tracing")]
+    pub fn generator_id(&self) -> u64 {
+        self.generator_id
+    }
+
     pub fn generate(&mut self) -> H {
         let handle_id = self.next_handle_id;
         self.next_handle_id += self.next_handle_id.wrapping_add(1);
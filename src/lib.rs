@@ -1,9 +1,21 @@
 #![forbid(unsafe_code)]
 #![deny(missing_debug_implementations)]
+
+#[macro_use]
+mod trace;
+
+mod buffer;
 pub mod client;
+#[cfg(feature = "client_driver")]
+pub mod client_driver;
+mod esearch;
+#[cfg(feature = "event_stream")]
+pub mod event_stream;
 mod handle;
+mod multiappend;
 mod receive;
 mod send;
 pub mod server;
 pub mod stream;
 pub mod types;
+mod utf7;
@@ -0,0 +1,123 @@
+//! Decoding of `APPEND` with more than one message (`MULTIAPPEND`, RFC 3502).
+//!
+//! imap-codec only decodes an `APPEND` with a single message, so
+//! [`ServerFlow`](crate::server::ServerFlow) decodes every message on its own as an `APPEND`
+//! with the tag and mailbox of the `MULTIAPPEND`.
+
+use bounded_static::IntoBoundedStatic;
+use imap_codec::{
+    decode::{CommandDecodeError, Decoder},
+    imap_types::{
+        command::{Command, CommandBody},
+        core::{LiteralMode, Tag},
+    },
+    CommandCodec,
+};
+
+use crate::receive::find_literal_announcement;
+
+// Prepended to every message after the first one, the tag and mailbox are replaced afterwards.
+const APPEND_PREFIX: &[u8] = b"A APPEND INBOX";
+
+pub(crate) enum MultiAppend {
+    /// The next message announced a literal that was not received yet.
+    LiteralFound {
+        tag: Tag<'static>,
+        length: u32,
+        mode: LiteralMode,
+    },
+    /// All messages were received, one `APPEND` per message.
+    Complete(Vec<Command<'static>>),
+}
+
+/// Decodes a `MULTIAPPEND` from the bytes received so far, including the last line ending.
+///
+/// Returns `None` if the bytes are not a `MULTIAPPEND` with at least two messages.
+pub(crate) fn decode(message: &[u8]) -> Option<MultiAppend> {
+    // Split the message after every literal.
+    let mut literal_ends = Vec::new();
+    let mut position = 0;
+    let mut rest = loop {
+        let line_end = position + message[position..].iter().position(|&b| b == b'\n')? + 1;
+
+        match find_literal_announcement(&message[position..line_end]) {
+            Some(announcement) if line_end < message.len() => {
+                position = line_end.checked_add(announcement.length as usize)?;
+                if position > message.len() {
+                    return None;
+                }
+                literal_ends.push(position);
+            }
+            _ => break &message[position..],
+        }
+    };
+
+    let (first, following) = literal_ends.split_first()?;
+
+    let mut first_message = message[..*first].to_vec();
+    first_message.extend_from_slice(b"\r\n");
+    let Command { tag, body } = decode_command(&first_message)?;
+    let CommandBody::Append { mailbox, .. } = &body else {
+        return None;
+    };
+    let mailbox = mailbox.clone();
+
+    let mut commands = vec![Command {
+        tag: tag.clone(),
+        body,
+    }];
+    let mut start = *first;
+    for end in following {
+        let mut next_message = APPEND_PREFIX.to_vec();
+        next_message.extend_from_slice(next_part(&message[start..*end])?);
+        next_message.extend_from_slice(b"\r\n");
+
+        let CommandBody::Append {
+            flags,
+            date,
+            message,
+            ..
+        } = decode_command(&next_message)?.body
+        else {
+            return None;
+        };
+        commands.push(Command {
+            tag: tag.clone(),
+            body: CommandBody::Append {
+                mailbox: mailbox.clone(),
+                flags,
+                date,
+                message,
+            },
+        });
+        start = *end;
+    }
+
+    if find_literal_announcement(rest).is_some() {
+        let mut next_message = APPEND_PREFIX.to_vec();
+        next_message.extend_from_slice(next_part(rest)?);
+
+        return match CommandCodec::default().decode(&next_message) {
+            Err(CommandDecodeError::LiteralFound { length, mode, .. }) => {
+                Some(MultiAppend::LiteralFound { tag, length, mode })
+            }
+            _ => None,
+        };
+    }
+
+    rest = rest.strip_suffix(b"\n")?;
+    rest = rest.strip_suffix(b"\r").unwrap_or(rest);
+    (rest.is_empty() && commands.len() > 1).then_some(MultiAppend::Complete(commands))
+}
+
+// Every message after the first one is separated by a space.
+fn next_part(part: &[u8]) -> Option<&[u8]> {
+    part.starts_with(b" ").then_some(part)
+}
+
+fn decode_command(bytes: &[u8]) -> Option<Command<'static>> {
+    match CommandCodec::default().decode(bytes) {
+        Ok((remaining, command)) if remaining.is_empty() => Some(command.into_static()),
+        _ => None,
+    }
+}
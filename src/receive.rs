@@ -1,14 +1,26 @@
 use bounded_static::IntoBoundedStatic;
-use bytes::{Buf, BytesMut};
-use imap_codec::decode::Decoder;
+use bytes::{Buf, Bytes, BytesMut};
+use imap_codec::{decode::Decoder, imap_types::core::LiteralMode};
 
-use crate::stream::{AnyStream, StreamError};
+use crate::{
+    buffer::{self, LITERAL_RESERVATION_STEP},
+    stream::{AnyStream, StreamError},
+};
+
+/// Decides whether the bytes of a decoded message are handed out instead of copying the message.
+pub type BorrowFilter<C> = for<'a> fn(&<C as Decoder>::Message<'a>) -> bool;
 
 #[derive(Debug)]
 pub struct ReceiveState<C: Decoder> {
     codec: C,
     crlf_relaxed: bool,
+    // Messages matching the filter are reported via `ReceiveEvent::DecodingSuccessBorrowed`.
+    borrow_filter: Option<BorrowFilter<C>>,
     next_fragment: NextFragment,
+    // Are we currently skipping a malformed message?
+    skipping: bool,
+    // Are synchronizing literals followed while skipping?
+    follow_sync_literals: bool,
     // How many bytes in the parse buffer do we already have checked?
     // This is important if we need multiple attempts to read from the underlying
     // stream before the message is completely received.
@@ -16,6 +28,18 @@ pub struct ReceiveState<C: Decoder> {
     // Used for reading the current message from the stream.
     // Its length should always be equal to or greater than `seen_bytes`.
     read_buffer: BytesMut,
+    // Total number of discarded messages.
+    discarded_messages: u64,
+    // Report `ReceiveEvent::LiteralProgress` every time this many bytes were received.
+    literal_progress_granularity: Option<u32>,
+    // Number of bytes of the current literal that were already reported.
+    literal_progress_reported: u32,
+    // Number of literals of the current message that were started via `start_literal`.
+    started_literals: usize,
+    // Are 8-bit characters outside of literals accepted?
+    utf8_accepted: bool,
+    // Does a line of the current message contain 8-bit characters?
+    contains_8bit: bool,
 }
 
 impl<C: Decoder> ReceiveState<C> {
@@ -23,29 +47,191 @@ impl<C: Decoder> ReceiveState<C> {
         Self {
             codec,
             crlf_relaxed,
+            borrow_filter: None,
             next_fragment: NextFragment::default(),
+            skipping: false,
+            follow_sync_literals: false,
             seen_bytes: 0,
             read_buffer,
+            discarded_messages: 0,
+            literal_progress_granularity: None,
+            literal_progress_reported: 0,
+            started_literals: 0,
+            utf8_accepted: false,
+            contains_8bit: false,
         }
     }
 
+    /// Returns the number of bytes in the read buffer.
+    pub fn buffered_bytes(&self) -> usize {
+        self.read_buffer.len()
+    }
+
+    /// Returns the capacity of the read buffer.
+    pub fn buffer_capacity(&self) -> usize {
+        self.read_buffer.capacity()
+    }
+
+    /// Returns the total number of discarded messages.
+    pub fn discarded_messages(&self) -> u64 {
+        self.discarded_messages
+    }
+
+    /// Returns `true` if no bytes of the current message were processed yet.
+    ///
+    /// Note: Bytes of an incomplete line may already be buffered.
+    pub fn is_message_boundary(&self) -> bool {
+        self.seen_bytes == 0 && !self.skipping && matches!(self.next_fragment, NextFragment::Line)
+    }
+
+    pub fn set_crlf_relaxed(&mut self, crlf_relaxed: bool) {
+        self.crlf_relaxed = crlf_relaxed;
+    }
+
+    pub fn set_borrow_filter(&mut self, borrow_filter: Option<BorrowFilter<C>>) {
+        self.borrow_filter = borrow_filter;
+    }
+
+    /// Accepts 8-bit characters outside of literals, e.g., UTF-8 in quoted strings.
+    ///
+    /// IMAP4rev1 only allows them after `UTF8=ACCEPT` was enabled (RFC 6855). Otherwise, a
+    /// message containing them is returned as [`ReceiveEvent::Unexpected8Bit`]. Should only be
+    /// changed at a message boundary.
+    pub fn set_utf8_accepted(&mut self, utf8_accepted: bool) {
+        self.utf8_accepted = utf8_accepted;
+    }
+
+    /// Enables [`ReceiveEvent::LiteralProgress`] for accepted literals.
+    ///
+    /// An event is returned whenever at least `granularity` bytes were received since the last
+    /// event. Skipped literals are never reported.
+    pub fn set_literal_progress_granularity(&mut self, granularity: Option<u32>) {
+        self.literal_progress_granularity = granularity;
+    }
+
+    /// Continues with receiving a literal with the given length.
+    ///
+    /// Note: The read buffer grows incrementally while the literal is received, see
+    /// [`LITERAL_RESERVATION_STEP`].
     pub fn start_literal(&mut self, length: u32) {
         self.next_fragment = NextFragment::Literal { length };
-        self.read_buffer.reserve(length as usize);
+        self.literal_progress_reported = 0;
+        self.started_literals += 1;
+    }
+
+    /// Returns the number of literals of the current message that were started via
+    /// [`ReceiveState::start_literal`].
+    ///
+    /// This is also the index of the next literal of the message.
+    pub fn started_literals(&self) -> usize {
+        self.started_literals
     }
 
+    /// Removes the current message from the read buffer.
+    ///
+    /// A large allocation, e.g., from a big literal, is released afterwards.
     pub fn finish_message(&mut self) {
         self.read_buffer.advance(self.seen_bytes);
+        buffer::shrink(&mut self.read_buffer, self.seen_bytes);
         self.seen_bytes = 0;
         self.next_fragment = NextFragment::default();
+        self.skipping = false;
+        self.started_literals = 0;
+        self.contains_8bit = false;
+    }
+
+    /// Removes the current message from the read buffer and returns its bytes without copying.
+    pub fn split_message(&mut self) -> Bytes {
+        let bytes = self.read_buffer.split_to(self.seen_bytes).freeze();
+        // The bytes were already removed from the read buffer.
+        self.seen_bytes = 0;
+        self.finish_message();
+        bytes
+    }
+
+    /// Decodes the bytes of a [`ReceiveEvent::DecodingSuccessBorrowed`] again and copies the
+    /// message.
+    ///
+    /// This allows handling a borrowed message like any other message.
+    pub fn decode_static(&self, bytes: &[u8]) -> Option<C::Message<'static>>
+    where
+        for<'a> C::Message<'a>: IntoBoundedStatic<Static = C::Message<'static>>,
+    {
+        match self.codec.decode(bytes) {
+            Ok((_, message)) => Some(message.into_static()),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns the bytes of the current message.
+    pub fn message(&self) -> &[u8] {
+        &self.read_buffer[..self.seen_bytes]
     }
 
     pub fn discard_message(&mut self) -> Box<[u8]> {
-        let discarded_bytes = self.read_buffer[..self.seen_bytes].into();
+        let discarded_bytes: Box<[u8]> = self.read_buffer[..self.seen_bytes].into();
         self.finish_message();
+        self.discarded_messages += 1;
+        debug!(byte_count = discarded_bytes.len(), "Discarded message");
         discarded_bytes
     }
 
+    /// Returns `true` if the current line announces a literal8 (RFC 3516), e.g., `~{42}\r\n`.
+    ///
+    /// The decoder reports literals and literal8s in the same way, so the announcement is
+    /// checked separately.
+    pub fn is_literal8_announced(&self) -> bool {
+        matches!(
+            find_literal_announcement(&self.read_buffer[..self.seen_bytes]),
+            Some(announcement) if announcement.binary
+        )
+    }
+
+    /// Skips a literal with the given length and the rest of the message.
+    ///
+    /// Synchronizing literals announced by the rest of the message are only followed if
+    /// `follow_sync_literals` is `true`, see [`ReceiveState::skip_message`].
+    ///
+    /// [`ReceiveState::progress`] must be called until it returns
+    /// [`ReceiveEvent::MessageSkipped`].
+    pub fn skip_literal(&mut self, length: u32, follow_sync_literals: bool) {
+        self.skipping = true;
+        self.follow_sync_literals = follow_sync_literals;
+        self.next_fragment = NextFragment::Literal { length };
+    }
+
+    /// Skips the rest of the current (malformed) message.
+    ///
+    /// Usually the message ends with the current line. However, if the line announces a literal,
+    /// the literal and all following lines up to the end of the message must be skipped, too.
+    /// Otherwise, the literal would be interpreted as the next message.
+    ///
+    /// Synchronizing literals, including the ones announced by later lines of the message, are
+    /// only followed if `follow_sync_literals` is `true`. This is required for the server side
+    /// because a client won't send a synchronizing literal before it received a continuation
+    /// request.
+    ///
+    /// Returns `true` if the message is completely skipped and can be discarded via
+    /// [`ReceiveState::discard_message`]. Otherwise, [`ReceiveState::progress`] must be called
+    /// until it returns [`ReceiveEvent::MessageSkipped`].
+    pub fn skip_message(&mut self, follow_sync_literals: bool) -> bool {
+        match find_literal_announcement(&self.read_buffer[..self.seen_bytes]) {
+            Some(LiteralAnnouncement { length, mode, .. })
+                if follow_sync_literals || mode == LiteralMode::NonSync =>
+            {
+                self.skipping = true;
+                self.follow_sync_literals = follow_sync_literals;
+                self.next_fragment = NextFragment::Literal { length };
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Receives the next message.
+    ///
+    /// Note: This method is cancel safe because the state is only changed between the reads
+    /// from the stream.
     pub async fn progress(&mut self, stream: &mut AnyStream) -> Result<ReceiveEvent<C>, StreamError>
     where
         for<'a> C::Message<'a>: IntoBoundedStatic<Static = C::Message<'static>>,
@@ -59,7 +245,9 @@ impl<C: Decoder> ReceiveState<C> {
                     }
                 }
                 NextFragment::Literal { length } => {
-                    self.progress_literal(stream, length).await?;
+                    if let Some(event) = self.progress_literal(stream, length).await? {
+                        return Ok(event);
+                    }
                 }
             };
         }
@@ -85,53 +273,148 @@ impl<C: Decoder> ReceiveState<C> {
         };
 
         // Mark the all bytes of the current line as seen.
+        let line_start = self.seen_bytes;
         self.seen_bytes += crlf_result.lf_position + 1;
 
+        if self.skipping {
+            // We don't decode the lines of a skipped message, we only look for more literals.
+            return Ok(
+                match find_literal_announcement(&self.read_buffer[..self.seen_bytes]) {
+                    Some(LiteralAnnouncement { length, mode, .. })
+                        if self.follow_sync_literals || mode == LiteralMode::NonSync =>
+                    {
+                        self.next_fragment = NextFragment::Literal { length };
+                        None
+                    }
+                    // The peer won't send a synchronizing literal that we don't accept, so the
+                    // message ends here.
+                    _ => Some(ReceiveEvent::MessageSkipped),
+                },
+            );
+        }
+
         if crlf_result.expected_crlf_got_lf {
             return Ok(Some(ReceiveEvent::ExpectedCrlfGotLf));
         }
 
+        // Literals are skipped by `progress_literal`, so only the lines are checked.
+        if !self.read_buffer[line_start..self.seen_bytes].is_ascii() {
+            self.contains_8bit = true;
+        }
+
         // Try to parse the whole message from the start (including the new line).
         // TODO: If the message is really long and we need multiple attempts to receive it, then this is O(n^2)
         //       IMO this can be only fixed by using a generator-like decoder
-        match self.codec.decode(&self.read_buffer[..self.seen_bytes]) {
+        let message = match self.codec.decode(&self.read_buffer[..self.seen_bytes]) {
             Ok((remaining, message)) => {
                 assert!(remaining.is_empty());
-                Ok(Some(ReceiveEvent::DecodingSuccess(message.into_static())))
+                message
             }
-            Err(error) => Ok(Some(ReceiveEvent::DecodingFailure(error.into_static()))),
+            Err(error) => return Ok(Some(ReceiveEvent::DecodingFailure(error.into_static()))),
+        };
+
+        if self.contains_8bit && !self.utf8_accepted {
+            return Ok(Some(ReceiveEvent::Unexpected8Bit));
         }
+
+        if matches!(self.borrow_filter, Some(filter) if filter(&message)) {
+            // Don't copy the message, hand out the received bytes instead.
+            drop(message);
+            return Ok(Some(ReceiveEvent::DecodingSuccessBorrowed(
+                self.split_message(),
+            )));
+        }
+
+        Ok(Some(ReceiveEvent::DecodingSuccess(message.into_static())))
     }
 
     async fn progress_literal(
         &mut self,
         stream: &mut AnyStream,
         literal_length: u32,
-    ) -> Result<(), StreamError> {
+    ) -> Result<Option<ReceiveEvent<C>>, StreamError> {
         let unseen_bytes = self.read_buffer.len() - self.seen_bytes;
 
         if unseen_bytes < literal_length as usize {
             // We did not receive enough bytes for the literal yet.
+            // Don't trust the announced length, only reserve a bounded chunk per read.
+            let missing_bytes = literal_length as usize - unseen_bytes;
+            self.read_buffer
+                .reserve(missing_bytes.min(LITERAL_RESERVATION_STEP));
             stream.read(&mut self.read_buffer).await?;
-        } else {
-            // We received enough bytes for the literal.
-            // Now we can continue reading the next line.
-            self.next_fragment = NextFragment::Line;
-            self.seen_bytes += literal_length as usize;
+
+            return Ok(self.literal_progress(literal_length));
         }
 
-        Ok(())
+        // We received enough bytes for the literal.
+        // Now we can continue reading the next line.
+        self.next_fragment = NextFragment::Line;
+        self.seen_bytes += literal_length as usize;
+
+        Ok(None)
+    }
+
+    // Returns a `ReceiveEvent::LiteralProgress` if enough bytes were received since the last one.
+    fn literal_progress(&mut self, literal_length: u32) -> Option<ReceiveEvent<C>> {
+        let granularity = self.literal_progress_granularity?;
+
+        if self.skipping {
+            return None;
+        }
+
+        // The buffer might already contain bytes after the literal.
+        let received = (self.read_buffer.len() - self.seen_bytes).min(literal_length as usize);
+        // `received` is limited by `literal_length`, so it fits into `u32`.
+        let received = received as u32;
+
+        if received - self.literal_progress_reported < granularity {
+            return None;
+        }
+
+        self.literal_progress_reported = received;
+
+        Some(ReceiveEvent::LiteralProgress {
+            announced: literal_length,
+            received,
+        })
     }
 
     pub fn change_codec<D: Decoder>(self, codec: D) -> ReceiveState<D> {
-        ReceiveState::new(codec, self.crlf_relaxed, self.read_buffer)
+        ReceiveState {
+            discarded_messages: self.discarded_messages,
+            literal_progress_granularity: self.literal_progress_granularity,
+            utf8_accepted: self.utf8_accepted,
+            ..ReceiveState::new(codec, self.crlf_relaxed, self.read_buffer)
+        }
     }
 }
 
 pub enum ReceiveEvent<C: Decoder> {
     DecodingSuccess(C::Message<'static>),
+    /// The message was decoded successfully and matched the [`BorrowFilter`].
+    ///
+    /// The bytes of the message were split off the read buffer without copying, see
+    /// [`ReceiveState::split_message`]. The message must be decoded from them again because it
+    /// can't borrow from the read buffer.
+    DecodingSuccessBorrowed(Bytes),
     DecodingFailure(C::Error<'static>),
+    /// The message was decoded successfully but contains 8-bit characters outside of literals
+    /// although they are not accepted, see [`ReceiveState::set_utf8_accepted`].
+    ///
+    /// The message must be handled like a malformed message.
+    Unexpected8Bit,
     ExpectedCrlfGotLf,
+    /// Bytes of an accepted literal were received.
+    ///
+    /// Only returned if enabled via [`ReceiveState::set_literal_progress_granularity`]. The
+    /// message is still incomplete, [`ReceiveState::progress`] must be called again.
+    LiteralProgress {
+        announced: u32,
+        received: u32,
+    },
+    /// The message was completely skipped after calling [`ReceiveState::skip_message`] or
+    /// [`ReceiveState::skip_literal`].
+    MessageSkipped,
 }
 
 // The next fragment that will be read...
@@ -148,6 +431,44 @@ enum NextFragment {
     },
 }
 
+// A literal announcement at the end of a line, e.g. `{42}\r\n`, `{42+}\r\n`, or `~{42}\r\n`.
+pub struct LiteralAnnouncement {
+    pub length: u32,
+    pub mode: LiteralMode,
+    // Is it a literal8 (RFC 3516)?
+    pub binary: bool,
+}
+
+// Finds the literal announcement at the end of the current line.
+//
+// Note: This is only a best-effort approach for malformed messages, well-formed messages are
+// handled by the decoder.
+pub fn find_literal_announcement(buf: &[u8]) -> Option<LiteralAnnouncement> {
+    let line = buf.strip_suffix(b"\n")?;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let line = line.strip_suffix(b"}")?;
+
+    // `LITERAL+` uses `{42+}` and `LITERAL-` uses `{42-}` for non-synchronizing literals.
+    let (line, mode) = match line.strip_suffix(b"+").or_else(|| line.strip_suffix(b"-")) {
+        Some(line) => (line, LiteralMode::NonSync),
+        None => (line, LiteralMode::Sync),
+    };
+
+    let digit_count = line.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+    let (line, digits) = line.split_at(line.len() - digit_count);
+    let line = line.strip_suffix(b"{")?;
+    let binary = line.ends_with(b"~");
+
+    // Fails for an empty or too large number.
+    let length = std::str::from_utf8(digits).ok()?.parse().ok()?;
+
+    Some(LiteralAnnouncement {
+        length,
+        mode,
+        binary,
+    })
+}
+
 // A line ending for the current line was found.
 struct FindCrlfResult {
     // The position of the `\n` symbol
@@ -1,20 +1,37 @@
 use std::{collections::VecDeque, fmt::Debug};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use imap_codec::{
     encode::{Encoder, Fragment},
     imap_types::{
         auth::AuthenticateData,
         command::{Command, CommandBody},
+        core::{LiteralMode, Tag},
+        datetime::DateTime,
+        flag::Flag,
+        mailbox::Mailbox,
     },
     AuthenticateDataCodec, CommandCodec,
 };
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
 
 use crate::{
+    buffer::LITERAL_READER_CHUNK_SIZE,
     stream::{AnyStream, StreamError},
-    types::CommandAuthenticate,
+    types::{CommandAppend, CommandAuthenticate, LiteralReader},
 };
 
+/// Error during sending a command or response.
+#[derive(Debug, Error)]
+pub enum SendError {
+    #[error(transparent)]
+    Stream(#[from] StreamError),
+    /// Reading from a [`LiteralReader`] failed or it ended early.
+    #[error("Failed to read literal")]
+    LiteralReader(#[source] std::io::Error),
+}
+
 #[derive(Debug)]
 pub struct SendCommandState<K: Copy> {
     command_codec: CommandCodec,
@@ -68,13 +85,126 @@ impl<K: Copy> SendCommandState<K> {
             key,
             kind,
             fragments,
+            literal_readers: VecDeque::new(),
+        });
+    }
+
+    /// Enqueues an `APPEND` whose messages are read from [`LiteralReader`]s.
+    ///
+    /// The codec only supports a single message that is already in memory. Therefore, every
+    /// message is encoded with an empty placeholder literal. Later, the literal announcement is
+    /// replaced and the placeholder is sent from the corresponding reader.
+    ///
+    /// Returns the [`CommandAppend`] if it has no messages or the codec didn't encode the
+    /// placeholder as expected.
+    pub fn enqueue_append(&mut self, key: K, append: CommandAppend) -> Result<(), CommandAppend> {
+        if append.messages.is_empty() {
+            return Err(append);
+        }
+
+        // The line up to and including the mailbox, e.g., `A1 APPEND INBOX `.
+        let Some(prefix) = self.encode_append_line(&append.tag, &append.mailbox, Vec::new(), None)
+        else {
+            return Err(append);
+        };
+
+        let mut lines = Vec::with_capacity(append.messages.len());
+        for message in &append.messages {
+            match self.encode_append_line(
+                &append.tag,
+                &append.mailbox,
+                message.flags.clone(),
+                message.date.clone(),
+            ) {
+                Some(line) if line.starts_with(&prefix) => lines.push(line),
+                _ => return Err(append),
+            }
+        }
+
+        let CommandAppend { tag, messages, .. } = append;
+
+        let mut fragments = VecDeque::new();
+        let mut literal_readers = VecDeque::new();
+        for (index, (message, line)) in messages.into_iter().zip(lines).enumerate() {
+            let mut data = if index == 0 {
+                line
+            } else {
+                // Only the options of the message follow the previous message.
+                let mut data = b" ".to_vec();
+                data.extend_from_slice(&line[prefix.len()..]);
+                data
+            };
+            if message.binary {
+                data.push(b'~');
+            }
+            data.extend_from_slice(format!("{{{}}}\r\n", message.literal.length()).as_bytes());
+
+            fragments.push_back(Fragment::Line { data });
+            fragments.push_back(Fragment::Literal {
+                data: Vec::new(),
+                mode: LiteralMode::Sync,
+            });
+            literal_readers.push_back(message.literal);
+        }
+        fragments.push_back(Fragment::Line {
+            data: b"\r\n".to_vec(),
+        });
+
+        self.send_queue.push_back(SendCommandQueueEntry {
+            key,
+            kind: SendCommandKind::Append { tag },
+            fragments,
+            literal_readers,
         });
+
+        Ok(())
+    }
+
+    // Encodes the line of an `APPEND` up to the literal announcement (exclusive).
+    //
+    // Returns `None` if the line doesn't end with the announcement of the placeholder literal.
+    fn encode_append_line(
+        &self,
+        tag: &Tag<'static>,
+        mailbox: &Mailbox<'static>,
+        flags: Vec<Flag<'static>>,
+        date: Option<DateTime>,
+    ) -> Option<Vec<u8>> {
+        let command = Command {
+            tag: tag.clone(),
+            body: CommandBody::append(mailbox.clone(), flags, date, b"".as_ref()).ok()?,
+        };
+
+        match self.command_codec.encode(&command).next()? {
+            Fragment::Line { data } => data.strip_suffix(b"{0}\r\n").map(<[u8]>::to_vec),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if there are no commands left to send.
+    pub fn is_empty(&self) -> bool {
+        self.send_progress.is_none() && self.send_queue.is_empty()
+    }
+
+    /// Returns the number of commands left to send, including the one in progress.
+    pub fn len(&self) -> usize {
+        self.send_queue.len() + usize::from(self.send_progress.is_some())
     }
 
     pub fn command_in_progress(&self) -> Option<&SendCommandKind> {
         self.send_progress.as_ref().map(|x| &x.kind)
     }
 
+    /// Returns the number of literals of the command in progress that were accepted by the
+    /// server so far.
+    ///
+    /// This is also the index of the literal the command is currently waiting for.
+    pub fn accepted_literals(&self) -> Option<usize> {
+        self.send_progress
+            .as_ref()
+            .map(|progress| progress.accepted_literals)
+    }
+
     pub fn remove_command_in_progress(&mut self) -> Option<(K, SendCommandKind)> {
         self.write_buffer.clear();
         self.send_progress
@@ -82,6 +212,21 @@ impl<K: Copy> SendCommandState<K> {
             .map(|progress| (progress.key, progress.kind))
     }
 
+    /// Removes all commands that were not sent completely, including the one in progress.
+    pub fn drain(&mut self) -> Vec<(K, SendCommandKind)> {
+        self.write_buffer.clear();
+        let progress = self
+            .send_progress
+            .take()
+            .map(|progress| (progress.key, progress.kind));
+        let queue = self
+            .send_queue
+            .drain(..)
+            .map(|entry| (entry.key, entry.kind));
+
+        progress.into_iter().chain(queue).collect()
+    }
+
     pub fn continue_literal(&mut self) -> bool {
         let Some(write_progress) = self.send_progress.as_mut() else {
             return false;
@@ -100,6 +245,7 @@ impl<K: Copy> SendCommandState<K> {
         }
 
         *received_continue = true;
+        write_progress.accepted_literals += 1;
 
         true
     }
@@ -154,7 +300,7 @@ impl<K: Copy> SendCommandState<K> {
     pub async fn progress(
         &mut self,
         stream: &mut AnyStream,
-    ) -> Result<Option<SendCommandEvent<K>>, StreamError> {
+    ) -> Result<Option<SendCommandEvent<K>>, SendError> {
         let progress = match self.send_progress.take() {
             Some(progress) => {
                 // We are currently sending a command to the server. This sending process was
@@ -168,12 +314,23 @@ impl<K: Copy> SendCommandState<K> {
                     return Ok(None);
                 };
 
+                let literal_bytes_total = entry
+                    .literal_readers
+                    .iter()
+                    .map(|reader| u64::from(reader.length()))
+                    .sum();
+
                 // Start sending the next command
                 SendCommandProgress {
                     key: entry.key,
                     kind: entry.kind,
                     blocked_reason: None,
                     next_fragments: entry.fragments,
+                    literal_readers: entry.literal_readers,
+                    streamed_literal: None,
+                    literal_bytes_sent: 0,
+                    literal_bytes_total,
+                    accepted_literals: 0,
                 }
             }
         };
@@ -188,7 +345,16 @@ impl<K: Copy> SendCommandState<K> {
                 } => {
                     if received_continue {
                         // We received a `Continue` from the server, we can send the literal now
-                        self.write_buffer.extend(data);
+                        match progress.literal_readers.pop_front() {
+                            // The literal is a placeholder, the bytes are read from the reader.
+                            Some(reader) => {
+                                progress.streamed_literal = Some(StreamedLiteral {
+                                    remaining: reader.length(),
+                                    reader,
+                                })
+                            }
+                            None => self.write_buffer.extend(data),
+                        }
                     } else {
                         // Delay this literal because we still wait for the `Continue` from the server
                         progress.blocked_reason =
@@ -234,6 +400,50 @@ impl<K: Copy> SendCommandState<K> {
             }
         }
 
+        // Stream the literal chunk by chunk if there is one
+        if let Some(literal) = progress.streamed_literal.as_mut() {
+            // Note: The write buffer isn't empty if the `Future` was dropped while sending the
+            // last chunk. Reading is cancel safe because no bytes are read if the `Future` is
+            // dropped.
+            if self.write_buffer.is_empty() && literal.remaining > 0 {
+                let chunk_size = (literal.remaining as usize).min(LITERAL_READER_CHUNK_SIZE);
+                self.write_buffer.reserve(chunk_size);
+                let byte_count = literal
+                    .reader
+                    .reader
+                    .as_mut()
+                    .take(chunk_size as u64)
+                    .read_buf(&mut self.write_buffer)
+                    .await
+                    .map_err(SendError::LiteralReader)?;
+
+                if byte_count == 0 {
+                    // The reader ended before the announced length was reached.
+                    return Err(SendError::LiteralReader(
+                        std::io::ErrorKind::UnexpectedEof.into(),
+                    ));
+                }
+
+                // `byte_count` is limited by `remaining`, so it fits into `u32`.
+                literal.remaining -= byte_count as u32;
+                progress.literal_bytes_sent += byte_count as u64;
+            }
+
+            stream.write_all(&mut self.write_buffer).await?;
+
+            let event = SendCommandEvent::LiteralProgress {
+                key: progress.key,
+                bytes_sent: progress.literal_bytes_sent,
+                bytes_total: progress.literal_bytes_total,
+            };
+
+            if literal.remaining == 0 {
+                progress.streamed_literal = None;
+            }
+
+            return Ok(Some(event));
+        }
+
         // Handle the outstanding lines or literals
         let need_continue = loop {
             if let Some(fragment) = progress.next_fragments.pop_front() {
@@ -241,8 +451,19 @@ impl<K: Copy> SendCommandState<K> {
                     Fragment::Line { data } => {
                         self.write_buffer.extend(data);
                     }
-                    Fragment::Literal { data, mode: _mode } => {
-                        // TODO: Handle `LITERAL{+,-}`.
+                    Fragment::Literal {
+                        data,
+                        mode: LiteralMode::NonSync,
+                    } => {
+                        // `LITERAL+` and `LITERAL-` allow sending the literal right away. The
+                        // codec only produces it if the command was created this way.
+                        self.write_buffer.extend(data);
+                        progress.accepted_literals += 1;
+                    }
+                    Fragment::Literal {
+                        data,
+                        mode: LiteralMode::Sync,
+                    } => {
                         // Delay this literal because we need to wait for a `Continue` from
                         // the server
                         progress.blocked_reason =
@@ -279,6 +500,13 @@ impl<K: Copy> SendCommandState<K> {
                         command,
                     }))
                 }
+                SendCommandKind::Append { tag } => {
+                    // Command was sent completely
+                    Ok(Some(SendCommandEvent::AppendSent {
+                        key: progress.key,
+                        tag,
+                    }))
+                }
                 SendCommandKind::Authenticate {
                     command_authenticate,
                     started,
@@ -310,8 +538,25 @@ impl<K: Copy> SendCommandState<K> {
 }
 
 pub enum SendCommandEvent<K> {
-    CommandSent { key: K, command: Command<'static> },
-    CommandAuthenticateStarted { key: K },
+    CommandSent {
+        key: K,
+        command: Command<'static>,
+    },
+    CommandAuthenticateStarted {
+        key: K,
+    },
+    AppendSent {
+        key: K,
+        tag: Tag<'static>,
+    },
+    /// A chunk of a literal read from a [`LiteralReader`] was sent.
+    ///
+    /// The bytes are counted over all [`LiteralReader`]s of the command.
+    LiteralProgress {
+        key: K,
+        bytes_sent: u64,
+        bytes_total: u64,
+    },
 }
 
 // TODO: Better name?
@@ -324,6 +569,10 @@ pub enum SendCommandKind {
         command_authenticate: CommandAuthenticate,
         started: bool,
     },
+    /// `APPEND` enqueued via [`SendCommandState::enqueue_append`].
+    Append {
+        tag: Tag<'static>,
+    },
 }
 
 #[derive(Debug)]
@@ -331,6 +580,8 @@ struct SendCommandQueueEntry<K> {
     key: K,
     kind: SendCommandKind,
     fragments: VecDeque<Fragment>,
+    // Readers for the placeholder literals in `fragments`.
+    literal_readers: VecDeque<LiteralReader>,
 }
 
 #[derive(Debug)]
@@ -341,6 +592,23 @@ struct SendCommandProgress<K> {
     blocked_reason: Option<SendCommandBlockedReason>,
     // The fragments that need to be sent.
     next_fragments: VecDeque<Fragment>,
+    // Readers for the remaining placeholder literals in `next_fragments`.
+    literal_readers: VecDeque<LiteralReader>,
+    // The literal that is currently being read from a reader and sent.
+    streamed_literal: Option<StreamedLiteral>,
+    // Number of bytes read from all readers so far.
+    literal_bytes_sent: u64,
+    // Sum of the lengths of all readers.
+    literal_bytes_total: u64,
+    // Number of literals the server sent a `Continue` for.
+    accepted_literals: usize,
+}
+
+#[derive(Debug)]
+struct StreamedLiteral {
+    reader: LiteralReader,
+    // Number of bytes that were not read yet.
+    remaining: u32,
 }
 
 #[derive(Debug)]
@@ -360,18 +628,44 @@ enum SendCommandBlockedReason {
     },
 }
 
+/// A response sent by [`SendResponseState`].
+#[derive(Debug)]
+pub enum SendResponse<M> {
+    /// Response encoded by the codec.
+    Message(M),
+    /// Pre-encoded response written as is.
+    Raw(Bytes),
+    /// Pre-encoded response with a literal read from a [`LiteralReader`].
+    Streamed(StreamedResponse),
+}
+
+#[derive(Debug)]
+pub struct StreamedResponse {
+    // Bytes before the literal, including the literal announcement.
+    head: Bytes,
+    literal: LiteralReader,
+    // Number of literal bytes that were not read yet.
+    remaining: u32,
+    // Bytes after the literal. `None` after they were pushed to the write buffer.
+    tail: Option<Bytes>,
+}
+
 #[derive(Debug)]
 pub struct SendResponseState<C: Encoder, K>
 where
     C::Message<'static>: Debug,
 {
     codec: C,
+    // Maximum number of bytes of multiple responses that are written at once.
+    max_batch_size: usize,
     // The responses that should be sent.
     send_queue: VecDeque<SendResponseQueueEntry<C, K>>,
-    // State of the response that is currently being sent.
-    send_progress: Option<SendResponseProgress<C, K>>,
-    // Used for writing the current response to the stream.
-    // Should be empty if `send_in_progress_key` is `None`.
+    // The responses that are currently being sent.
+    send_progress: VecDeque<SendResponseProgress<C, K>>,
+    // The responses that were sent completely but not returned by `progress` yet.
+    sent: VecDeque<SendResponseProgress<C, K>>,
+    // Used for writing the current responses to the stream.
+    // Should be empty if `send_progress` is empty.
     write_buffer: BytesMut,
 }
 
@@ -382,80 +676,230 @@ where
     pub fn new(codec: C, write_buffer: BytesMut) -> Self {
         Self {
             codec,
+            max_batch_size: 0,
             send_queue: VecDeque::new(),
-            send_progress: None,
+            send_progress: VecDeque::new(),
+            sent: VecDeque::new(),
             write_buffer,
         }
     }
 
+    /// Sets the maximum number of bytes of multiple responses that are written at once.
+    ///
+    /// A response is never split, so a response larger than `max_batch_size` is still written
+    /// in one batch. `0` disables batching.
+    pub fn set_max_batch_size(&mut self, max_batch_size: usize) {
+        self.max_batch_size = max_batch_size;
+    }
+
     pub fn enqueue(&mut self, key: K, response: C::Message<'static>) {
         let fragments = self.codec.encode(&response).collect();
         let entry = SendResponseQueueEntry {
             key,
-            response,
+            response: SendResponse::Message(response),
             fragments,
         };
         self.send_queue.push_back(entry);
     }
 
+    /// Enqueues pre-encoded bytes that are written without encoding.
+    pub fn enqueue_raw(&mut self, key: K, bytes: Bytes) {
+        let entry = SendResponseQueueEntry {
+            key,
+            response: SendResponse::Raw(bytes),
+            fragments: Vec::new(),
+        };
+        self.send_queue.push_back(entry);
+    }
+
+    /// Enqueues pre-encoded bytes with a literal in between that is read while it is sent.
+    ///
+    /// The literal announcement is appended to `head`.
+    pub fn enqueue_streamed(&mut self, key: K, head: Bytes, literal: LiteralReader, tail: Bytes) {
+        let mut head = BytesMut::from(&head[..]);
+        head.extend_from_slice(format!("{{{}}}\r\n", literal.length()).as_bytes());
+
+        let entry = SendResponseQueueEntry {
+            key,
+            response: SendResponse::Streamed(StreamedResponse {
+                head: head.freeze(),
+                remaining: literal.length(),
+                literal,
+                tail: Some(tail),
+            }),
+            fragments: Vec::new(),
+        };
+        self.send_queue.push_back(entry);
+    }
+
+    /// Returns `true` if there are no responses left to send or to return.
+    pub fn is_empty(&self) -> bool {
+        self.sent.is_empty() && self.send_progress.is_empty() && self.send_queue.is_empty()
+    }
+
+    /// Returns the number of responses left to send, including the ones in progress.
+    pub fn len(&self) -> usize {
+        self.send_queue.len() + self.send_progress.len()
+    }
+
+    /// Removes all responses that were not sent completely, including the ones in progress.
+    pub fn drain(&mut self) -> Vec<(K, SendResponse<C::Message<'static>>)> {
+        self.write_buffer.clear();
+        let progress = self
+            .send_progress
+            .drain(..)
+            .map(|progress| (progress.key, progress.response));
+        let queue = self
+            .send_queue
+            .drain(..)
+            .map(|entry| (entry.key, entry.response));
+
+        progress.chain(queue).collect()
+    }
+
     pub fn finish(mut self) -> BytesMut {
         self.write_buffer.clear();
         self.write_buffer
     }
 
+    /// Sends the next responses and returns them one by one.
+    ///
+    /// Multiple queued responses are written at once, see [`SendResponseState::set_max_batch_size`].
+    /// The responses of a batch are returned by the following calls without writing again.
+    ///
+    /// A [`StreamedResponse`] always ends a batch. Its literal is read and written in chunks of
+    /// at most [`LITERAL_READER_CHUNK_SIZE`] bytes.
     pub async fn progress(
         &mut self,
         stream: &mut AnyStream,
-    ) -> Result<Option<(K, C::Message<'static>)>, StreamError> {
-        let progress = match self.send_progress.take() {
-            Some(progress) => {
-                // We are currently sending a response. This sending process was
-                // previously aborted because the `Future` was dropped while sending.
-                progress
-            }
-            None => {
-                let Some(entry) = self.send_queue.pop_front() else {
-                    // There is currently no response that need to be sent
-                    return Ok(None);
-                };
+    ) -> Result<Option<(K, SendResponse<C::Message<'static>>)>, SendError> {
+        if let Some(progress) = self.sent.pop_front() {
+            // The response was sent as part of a previous batch.
+            return Ok(Some((progress.key, progress.response)));
+        }
 
-                // Push the response to the write buffer
+        // If responses are in progress, the sending process was previously aborted because the
+        // `Future` was dropped while sending. Otherwise, we start a new batch.
+        if self.send_progress.is_empty() {
+            // Push the next responses to the write buffer
+            while let Some(entry) = self.send_queue.front() {
+                if let Some(previous) = self.send_progress.back() {
+                    // The literal is written in chunks after the batch.
+                    let ends_batch = matches!(previous.response, SendResponse::Streamed(_));
+                    let size = match &entry.response {
+                        SendResponse::Message(_) => {
+                            entry.fragments.iter().map(fragment_len).sum::<usize>()
+                        }
+                        SendResponse::Raw(bytes) => bytes.len(),
+                        SendResponse::Streamed(streamed) => streamed.head.len(),
+                    };
+
+                    if ends_batch || self.write_buffer.len() + size > self.max_batch_size {
+                        break;
+                    }
+                }
+
+                // This `unwrap` can't fail because we checked `front` above.
+                let entry = self.send_queue.pop_front().unwrap();
+                match &entry.response {
+                    SendResponse::Message(_) => {}
+                    SendResponse::Raw(bytes) => self.write_buffer.extend_from_slice(bytes),
+                    SendResponse::Streamed(streamed) => {
+                        self.write_buffer.extend_from_slice(&streamed.head)
+                    }
+                }
                 for fragment in entry.fragments {
                     let data = match fragment {
                         Fragment::Line { data } => data,
-                        // TODO: Handle `LITERAL{+,-}`.
-                        Fragment::Literal { data, mode: _mode } => data,
+                        // Only the client waits for a `Continue`, so the mode doesn't matter.
+                        Fragment::Literal { data, .. } => data,
                         Fragment::AuthData { data } => data,
                     };
                     self.write_buffer.extend(data);
                 }
 
-                SendResponseProgress {
+                self.send_progress.push_back(SendResponseProgress {
                     key: entry.key,
                     response: entry.response,
+                });
+            }
+
+            if self.send_progress.is_empty() {
+                // There is currently no response that need to be sent
+                return Ok(None);
+            }
+        }
+
+        loop {
+            // Send all bytes of the current responses
+            stream.write_all(&mut self.write_buffer).await?;
+
+            // A streamed response is always the last one of a batch.
+            let Some(SendResponseProgress {
+                response: SendResponse::Streamed(streamed),
+                ..
+            }) = self.send_progress.back_mut()
+            else {
+                break;
+            };
+
+            if streamed.remaining == 0 {
+                match streamed.tail.take() {
+                    Some(tail) => self.write_buffer.extend_from_slice(&tail),
+                    None => break,
                 }
+                continue;
             }
-        };
-        self.send_progress = Some(progress);
 
-        // Send all bytes of current response
-        stream.write_all(&mut self.write_buffer).await?;
+            // Read the next chunk of the literal.
+            // Note: This is cancel safe because no bytes are read if the future is dropped.
+            let chunk_size = (streamed.remaining as usize).min(LITERAL_READER_CHUNK_SIZE);
+            self.write_buffer.reserve(chunk_size);
+            let byte_count = streamed
+                .literal
+                .reader
+                .as_mut()
+                .take(chunk_size as u64)
+                .read_buf(&mut self.write_buffer)
+                .await
+                .map_err(SendError::LiteralReader)?;
+
+            if byte_count == 0 {
+                // The reader ended before the announced length was reached.
+                return Err(SendError::LiteralReader(
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+
+            // `byte_count` is limited by `remaining`, so it fits into `u32`.
+            streamed.remaining -= byte_count as u32;
+        }
 
-        // Response was sent completely
+        // Responses were sent completely
+        self.sent.append(&mut self.send_progress);
         Ok(self
-            .send_progress
-            .take()
+            .sent
+            .pop_front()
             .map(|progress| (progress.key, progress.response)))
     }
 }
 
+fn fragment_len(fragment: &Fragment) -> usize {
+    match fragment {
+        Fragment::Line { data } => data.len(),
+        Fragment::Literal { data, .. } => data.len(),
+        Fragment::AuthData { data } => data.len(),
+    }
+}
+
 #[derive(Debug)]
 struct SendResponseQueueEntry<C: Encoder, K>
 where
     C::Message<'static>: Debug,
 {
     key: K,
-    response: C::Message<'static>,
+    response: SendResponse<C::Message<'static>>,
+    // Empty for `SendResponse::Raw`.
     fragments: Vec<Fragment>,
 }
 
@@ -465,5 +909,5 @@ where
     C::Message<'static>: Debug,
 {
     key: K,
-    response: C::Message<'static>,
+    response: SendResponse<C::Message<'static>>,
 }
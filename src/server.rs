@@ -1,35 +1,95 @@
-use std::fmt::Debug;
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex, Weak},
+};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use imap_codec::{
-    decode::{AuthenticateDataDecodeError, CommandDecodeError},
+    decode::{AuthenticateDataDecodeError, CommandDecodeError, Decoder},
     imap_types::{
         auth::AuthenticateData,
         command::{Command, CommandBody},
-        core::Text,
-        response::{CommandContinuationRequest, Data, Greeting, Response, Status},
+        core::{LiteralMode, Tag, Text},
+        response::{
+            Bye, Code, CommandContinuationRequest, Data, Greeting, Response, Status, Tagged,
+        },
     },
     AuthenticateDataCodec, CommandCodec, GreetingCodec, ResponseCodec,
 };
 use thiserror::Error;
+use tokio::sync::Notify;
 
 use crate::{
     handle::{Handle, HandleGenerator, HandleGeneratorGenerator, RawHandle},
-    receive::{ReceiveEvent, ReceiveState},
-    send::SendResponseState,
+    multiappend::{self, MultiAppend},
+    receive::{find_literal_announcement, LiteralAnnouncement, ReceiveEvent, ReceiveState},
+    send::{SendError, SendResponse, SendResponseState},
     stream::{AnyStream, StreamError},
-    types::CommandAuthenticate,
+    types::{CommandAuthenticate, EnabledExtensions, LiteralReader, ProtocolVersion},
 };
 
-static HANDLE_GENERATOR_GENERATOR: HandleGeneratorGenerator<ServerFlowResponseHandle> =
+pub(crate) static HANDLE_GENERATOR_GENERATOR: HandleGeneratorGenerator<ServerFlowResponseHandle> =
     HandleGeneratorGenerator::new();
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ServerFlowOptions {
     pub crlf_relaxed: bool,
+    /// Maximum size of a literal.
+    ///
+    /// Larger literals are always rejected, even if [`ServerFlowOptions::announce_literals`] is
+    /// enabled.
     pub max_literal_size: u32,
     pub literal_accept_text: Text<'static>,
     pub literal_reject_text: Text<'static>,
+    /// Let the application decide whether a literal is accepted.
+    ///
+    /// When enabled, every literal that doesn't exceed [`ServerFlowOptions::max_literal_size`]
+    /// is reported via [`ServerFlowEvent::LiteralAnnounced`] and the application must call
+    /// [`ServerFlow::literal_accept`] or [`ServerFlow::literal_reject`] next.
+    pub announce_literals: bool,
+    /// Skip malformed commands instead of failing with [`ServerFlowError::MalformedMessage`].
+    ///
+    /// Non-synchronizing literals announced by a malformed command are skipped, too.
+    /// Synchronizing literals are never sent by the client because the server doesn't send a
+    /// continuation request for them. Skipped commands are reported via
+    /// [`ServerFlowEvent::MalformedMessageSkipped`].
+    pub skip_malformed_messages: bool,
+    /// Respond with `BAD` to malformed commands.
+    ///
+    /// The tag is extracted from the malformed command on a best-effort basis. An untagged
+    /// `BAD` is sent when no tag could be found.
+    pub reject_malformed_commands: bool,
+    pub malformed_command_text: Text<'static>,
+    /// Maximum number of consecutive malformed commands.
+    ///
+    /// When exceeded, a `BYE` is sent and [`ServerFlowError::TooManyMalformedCommands`] is
+    /// returned. `None` means unlimited.
+    pub max_consecutive_malformed_commands: Option<u32>,
+    pub too_many_malformed_commands_text: Text<'static>,
+    /// The server only implements IMAP4rev2 and doesn't advertise `IMAP4rev1`.
+    ///
+    /// See [`ServerFlow::protocol_version`].
+    pub imap4rev2_only: bool,
+    /// Maximum number of bytes of queued responses that are written to the stream at once.
+    ///
+    /// Coalescing small responses, e.g., many `FETCH` responses, reduces the number of writes
+    /// and TLS records, see `benches/batching.rs`. [`ServerFlowEvent::ResponseSent`] is still
+    /// returned for every response. A response is never split, so larger responses are written
+    /// on their own. `16 * 1024` fits into a single TLS record. `0` disables batching.
+    pub max_batch_size: usize,
+    /// Report the progress of literals received from the client.
+    ///
+    /// When set, [`ServerFlowEvent::LiteralProgress`] is returned whenever at least this many
+    /// bytes of an accepted literal were received since the last event, e.g., while a client
+    /// uploads a large message via `APPEND`. `None` disables the events.
+    pub literal_progress_granularity: Option<u32>,
+    /// Accept literal8 (`~{42}`) from the `BINARY` extension (RFC 3516).
+    ///
+    /// Only enable this if the server advertises `BINARY`. Otherwise, commands containing a
+    /// literal8, e.g., an `APPEND` with binary content, are rejected with `BAD [UNKNOWN-CTE]`
+    /// and [`ServerFlowError::Literal8Rejected`] is returned.
+    pub binary: bool,
 }
 
 impl Default for ServerFlowOptions {
@@ -43,6 +103,26 @@ impl Default for ServerFlowOptions {
             literal_accept_text: Text::unvalidated("..."),
             // Short unmeaning text
             literal_reject_text: Text::unvalidated("..."),
+            // Don't bother the application by default
+            announce_literals: false,
+            // Don't hide protocol violations by default
+            skip_malformed_messages: false,
+            // Don't interfere with the application by default
+            reject_malformed_commands: false,
+            // Short unmeaning text
+            malformed_command_text: Text::unvalidated("..."),
+            // Don't interfere with the application by default
+            max_consecutive_malformed_commands: None,
+            // Short unmeaning text
+            too_many_malformed_commands_text: Text::unvalidated("..."),
+            // Most clients still require IMAP4rev1 (Nov. 2023)
+            imap4rev2_only: false,
+            // Don't change the write pattern by default
+            max_batch_size: 0,
+            // Don't bother the application by default
+            literal_progress_granularity: None,
+            // The server must advertise `BINARY` first
+            binary: false,
         }
     }
 }
@@ -51,11 +131,36 @@ impl Default for ServerFlowOptions {
 pub struct ServerFlow {
     stream: AnyStream,
     options: ServerFlowOptions,
+    // Options that will be applied at the next message boundary.
+    pending_options: Option<ServerFlowOptions>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 
     handle_generator: HandleGenerator<ServerFlowResponseHandle>,
     send_response_state: SendResponseState<ResponseCodec, Option<ServerFlowResponseHandle>>,
     next_expected_message: NextExpectedMessage,
     receive_command_state: ServerReceiveState,
+    consecutive_malformed_commands: u32,
+    enabled: EnabledExtensions,
+    // Message counters, the remaining statistics are collected on demand.
+    statistics: ServerFlowStatistics,
+    // Literal waiting for the application's decision.
+    pending_literal: Option<PendingLiteral>,
+    // Are we skipping the rest of a command because its non-synchronizing literal was rejected?
+    skipping_rejected_command: bool,
+    // Tags of received commands whose tagged status was not enqueued yet.
+    //
+    // Note: A duplicate tag is recorded once per command.
+    tags_in_flight: Vec<Tag<'static>>,
+
+    // Responses enqueued via `ServerFlowSender`.
+    sender_queue: Arc<SenderQueue>,
+
+    // Was a `BYE` sent? The flow is closed after the remaining responses were sent.
+    bye_sent: bool,
+    closed: bool,
+    // Responses that will never be sent because the flow is closed.
+    unsent_responses: VecDeque<(ServerFlowResponseHandle, SendResponse<Response<'static>>)>,
 }
 
 impl ServerFlow {
@@ -70,39 +175,156 @@ impl ServerFlow {
             SendResponseState::new(GreetingCodec::default(), write_buffer);
         send_greeting_state.enqueue((), greeting);
         let greeting = loop {
-            if let Some(((), greeting)) = send_greeting_state.progress(&mut stream).await? {
-                break greeting;
+            match send_greeting_state.progress(&mut stream).await? {
+                Some(((), SendResponse::Message(greeting))) => break greeting,
+                Some(((), SendResponse::Raw(_) | SendResponse::Streamed(_))) => {
+                    // The greeting is never enqueued as raw bytes.
+                    unreachable!()
+                }
+                None => {}
             }
         };
 
         // Successfully sent greeting, construct instance
         let write_buffer = send_greeting_state.finish();
-        let send_response_state = SendResponseState::new(ResponseCodec::default(), write_buffer);
+        let mut send_response_state =
+            SendResponseState::new(ResponseCodec::default(), write_buffer);
+        send_response_state.set_max_batch_size(options.max_batch_size);
         let read_buffer = BytesMut::new();
-        let receive_command_state =
+        let mut receive_command_state =
             ReceiveState::new(CommandCodec::default(), options.crlf_relaxed, read_buffer);
+        receive_command_state
+            .set_literal_progress_granularity(options.literal_progress_granularity);
+        let handle_generator = HANDLE_GENERATOR_GENERATOR.generate();
         let server_flow = Self {
             stream,
             options,
-            handle_generator: HANDLE_GENERATOR_GENERATOR.generate(),
+            pending_options: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("server_flow", flow_id = handle_generator.generator_id()),
+            handle_generator,
             next_expected_message: NextExpectedMessage::Command,
             send_response_state,
             receive_command_state: ServerReceiveState::Command(receive_command_state),
+            consecutive_malformed_commands: 0,
+            enabled: EnabledExtensions::default(),
+            statistics: ServerFlowStatistics::default(),
+            pending_literal: None,
+            skipping_rejected_command: false,
+            tags_in_flight: Vec::new(),
+            sender_queue: Arc::default(),
+            bye_sent: false,
+            closed: false,
+            unsent_responses: VecDeque::new(),
         };
 
         Ok((server_flow, greeting))
     }
 
+    /// Returns a snapshot of the [`ServerFlowStatistics`].
+    pub fn statistics(&self) -> ServerFlowStatistics {
+        let (discarded_messages, read_buffer_len, read_buffer_capacity) =
+            match &self.receive_command_state {
+                ServerReceiveState::Command(state) => (
+                    state.discarded_messages(),
+                    state.buffered_bytes(),
+                    state.buffer_capacity(),
+                ),
+                ServerReceiveState::AuthenticateData(state) => (
+                    state.discarded_messages(),
+                    state.buffered_bytes(),
+                    state.buffer_capacity(),
+                ),
+                ServerReceiveState::Dummy => unreachable!(),
+            };
+
+        ServerFlowStatistics {
+            bytes_read: self.stream.bytes_read(),
+            bytes_written: self.stream.bytes_written(),
+            discarded_messages,
+            send_queue_len: self.send_response_state.len(),
+            read_buffer_len,
+            read_buffer_capacity,
+            ..self.statistics
+        }
+    }
+
+    /// Returns the extensions enabled via `ENABLE`.
+    ///
+    /// The flow observes `ENABLED` responses sent to the client. Before `UTF8=ACCEPT` or
+    /// `IMAP4rev2` is enabled, commands with 8-bit characters outside of literals are handled
+    /// like malformed commands. Afterwards, they are left to the decoder from the next command on.
+    pub fn enabled(&self) -> EnabledExtensions {
+        self.enabled
+    }
+
+    /// Returns the [`ProtocolVersion`] used with the client.
+    ///
+    /// This is [`ProtocolVersion::Imap4rev2`] after `ENABLE IMAP4rev2` or when
+    /// [`ServerFlowOptions::imap4rev2_only`] is set.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        if self.options.imap4rev2_only {
+            ProtocolVersion::Imap4rev2
+        } else {
+            self.enabled.protocol_version()
+        }
+    }
+
+    /// Overrides the extensions enabled via `ENABLE`.
+    ///
+    /// This is useful when the extensions were enabled without the flow observing it.
+    pub fn set_enabled(&mut self, enabled: EnabledExtensions) {
+        self.enabled = enabled;
+    }
+
+    /// Returns the [`ServerFlowOptions`] currently in effect.
+    pub fn options(&self) -> &ServerFlowOptions {
+        &self.options
+    }
+
+    /// Changes the [`ServerFlowOptions`].
+    ///
+    /// The options take effect at the next message boundary. If a command is currently being
+    /// received, e.g., while waiting for a literal, it is still processed with the old options.
+    /// This allows, e.g., to raise [`ServerFlowOptions::max_literal_size`] after authentication.
+    pub fn set_options(&mut self, options: ServerFlowOptions) {
+        self.pending_options = Some(options);
+        self.apply_pending_options();
+    }
+
+    fn apply_pending_options(&mut self) {
+        if self.pending_literal.is_some() || !self.receive_command_state.is_message_boundary() {
+            return;
+        }
+
+        // UTF-8 is accepted from the command after `ENABLED` on.
+        let utf8_accepted =
+            self.enabled.utf8_accept || self.protocol_version() == ProtocolVersion::Imap4rev2;
+        self.receive_command_state.set_utf8_accepted(utf8_accepted);
+
+        if let Some(options) = self.pending_options.take() {
+            self.receive_command_state
+                .set_crlf_relaxed(options.crlf_relaxed);
+            self.receive_command_state
+                .set_literal_progress_granularity(options.literal_progress_granularity);
+            self.send_response_state
+                .set_max_batch_size(options.max_batch_size);
+            self.options = options;
+        }
+    }
+
     /// Enqueues the [`Data`] response for being sent to the client.
     ///
     /// The response is not sent immediately but during one of the next calls of
     /// [`ServerFlow::progress`]. All responses are sent in the same order they have been
     /// enqueued.
-    pub fn enqueue_data(&mut self, data: Data<'static>) -> ServerFlowResponseHandle {
-        let handle = self.handle_generator.generate();
-        self.send_response_state
-            .enqueue(Some(handle), Response::Data(data));
-        handle
+    ///
+    /// Returns [`ServerFlowEnqueueError`] if the flow is closed.
+    pub fn enqueue_data(
+        &mut self,
+        data: Data<'static>,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
+        self.enqueue_response(Response::Data(data))
     }
 
     /// Enqueues the [`Status`] response for being sent to the client.
@@ -110,11 +332,13 @@ impl ServerFlow {
     /// The response is not sent immediately but during one of the next calls of
     /// [`ServerFlow::progress`]. All responses are sent in the same order they have been
     /// enqueued.
-    pub fn enqueue_status(&mut self, status: Status<'static>) -> ServerFlowResponseHandle {
-        let handle = self.handle_generator.generate();
-        self.send_response_state
-            .enqueue(Some(handle), Response::Status(status));
-        handle
+    ///
+    /// Returns [`ServerFlowEnqueueError`] if the flow is closed.
+    pub fn enqueue_status(
+        &mut self,
+        status: Status<'static>,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
+        self.enqueue_response(Response::Status(status))
     }
 
     /// Enqueues the [`CommandContinuationRequest`] response for being sent to the client.
@@ -122,19 +346,194 @@ impl ServerFlow {
     /// The response is not sent immediately but during one of the next calls of
     /// [`ServerFlow::progress`]. All responses are sent in the same order they have been
     /// enqueued.
+    ///
+    /// Returns [`ServerFlowEnqueueError`] if the flow is closed.
     pub fn enqueue_continuation(
         &mut self,
         continuation: CommandContinuationRequest<'static>,
-    ) -> ServerFlowResponseHandle {
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
+        self.enqueue_response(Response::CommandContinuationRequest(continuation))
+    }
+
+    fn enqueue_response(
+        &mut self,
+        response: Response<'static>,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
         let handle = self.handle_generator.generate();
-        self.send_response_state.enqueue(
-            Some(handle),
-            Response::CommandContinuationRequest(continuation),
+        self.enqueue_response_with_handle(handle, response)?;
+        Ok(handle)
+    }
+
+    // Enqueues a response whose handle was generated elsewhere, e.g., by a `ServerFlowSink`.
+    pub(crate) fn enqueue_response_with_handle(
+        &mut self,
+        handle: ServerFlowResponseHandle,
+        response: Response<'static>,
+    ) -> Result<(), ServerFlowEnqueueError> {
+        if self.closed {
+            return Err(ServerFlowEnqueueError { response });
+        }
+
+        self.complete_response_tag(&response);
+        self.send_response_state.enqueue(Some(handle), response);
+        Ok(())
+    }
+
+    /// Enqueues pre-encoded response bytes for being sent to the client.
+    ///
+    /// The bytes must consist of one or more complete responses, e.g., a cached `FETCH`
+    /// response. They are written as they are, so neither building nor encoding a [`Response`]
+    /// is necessary. [`ServerFlowEvent::RawResponseSent`] is returned after they were sent.
+    /// In debug builds, the bytes are validated by decoding them.
+    ///
+    /// Note: The flow doesn't inspect the bytes, so a `BYE` or `ENABLED` contained in them has
+    /// no effect on the flow. Use [`ServerFlow::enqueue_status`] and [`ServerFlow::enqueue_data`]
+    /// for these.
+    ///
+    /// Returns [`ServerFlowEnqueueRawError`] if the flow is closed.
+    pub fn enqueue_raw(
+        &mut self,
+        bytes: Bytes,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueRawError> {
+        debug_assert!(
+            is_complete_responses(&bytes),
+            "raw bytes must consist of complete responses"
         );
-        handle
+
+        if self.closed {
+            return Err(ServerFlowEnqueueRawError { bytes });
+        }
+
+        for tag in extract_raw_tags(&bytes) {
+            self.complete_tag(&tag);
+        }
+        let handle = self.handle_generator.generate();
+        self.send_response_state.enqueue_raw(Some(handle), bytes);
+        Ok(handle)
+    }
+
+    /// Enqueues a pre-encoded response with a literal that is read while it is sent.
+    ///
+    /// The response is written as `head`, the literal announcement, the bytes of the
+    /// [`LiteralReader`], and `tail`. It can't be a tagged status because these don't contain
+    /// literals. E.g., for `* 1 FETCH (BODY[] {5}\r\nhello)\r\n`, `head` is
+    /// `* 1 FETCH (BODY[] ` and `tail` is `)\r\n`. The literal is copied to the stream in chunks,
+    /// so serving a large message body doesn't require loading it into memory.
+    /// [`ServerFlowEvent::StreamedResponseSent`] is returned after the response was sent.
+    ///
+    /// Note: If the reader fails or ends early, [`ServerFlowError::LiteralReader`] is returned
+    /// and the flow is closed because the response can't be completed.
+    ///
+    /// Returns [`ServerFlowEnqueueStreamedError`] if the flow is closed.
+    pub fn enqueue_streamed(
+        &mut self,
+        head: Bytes,
+        literal: LiteralReader,
+        tail: Bytes,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueStreamedError> {
+        if self.closed {
+            return Err(ServerFlowEnqueueStreamedError {
+                head,
+                literal,
+                tail,
+            });
+        }
+
+        let handle = self.handle_generator.generate();
+        self.send_response_state
+            .enqueue_streamed(Some(handle), head, literal, tail);
+        Ok(handle)
+    }
+
+    /// Returns a [`ServerFlowSender`] for enqueuing responses from other tasks.
+    ///
+    /// [`ServerFlow::progress`] wakes up to send these responses, even while it is waiting for
+    /// the next command from the client.
+    pub fn sender(&self) -> ServerFlowSender {
+        ServerFlowSender {
+            handle_generator: HANDLE_GENERATOR_GENERATOR.generate(),
+            queue: Arc::downgrade(&self.sender_queue),
+        }
+    }
+
+    /// Returns `true` if the flow is closed.
+    ///
+    /// The flow is closed after a `BYE` and all responses enqueued until then were sent, or if
+    /// the stream failed. This allows sending the tagged status of `LOGOUT` after the `BYE`.
+    pub fn is_closed(&self) -> bool {
+        self.closed
     }
 
+    /// Progresses the flow and returns the next event.
+    ///
+    /// After the flow was closed, all responses that were not sent completely are returned via
+    /// [`ServerFlowEvent::ResponseUnsent`] first. Afterwards, [`ServerFlowError::Closed`] is
+    /// returned.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the returned future is dropped before completion, e.g.,
+    /// because another branch of `tokio::select!` completed first, no bytes are lost or
+    /// duplicated. Partially sent responses and partially received commands are kept in the flow
+    /// and progressed by the next call.
     pub async fn progress(&mut self) -> Result<ServerFlowEvent, ServerFlowError> {
+        instrument!(self.progress_instrumented(), self.span).await
+    }
+
+    async fn progress_instrumented(&mut self) -> Result<ServerFlowEvent, ServerFlowError> {
+        if self.closed {
+            return self.progress_closed();
+        }
+
+        let result = self.progress_open().await;
+
+        if let Err(ServerFlowError::Stream(_) | ServerFlowError::LiteralReader(_)) = result {
+            // We can't rely on the stream anymore.
+            self.close();
+        }
+
+        result
+    }
+
+    fn progress_closed(&mut self) -> Result<ServerFlowEvent, ServerFlowError> {
+        match self.unsent_responses.pop_front() {
+            Some((handle, SendResponse::Message(response))) => {
+                Ok(ServerFlowEvent::ResponseUnsent { handle, response })
+            }
+            Some((handle, SendResponse::Raw(bytes))) => {
+                Ok(ServerFlowEvent::RawResponseUnsent { handle, bytes })
+            }
+            Some((handle, SendResponse::Streamed(_))) => {
+                Ok(ServerFlowEvent::StreamedResponseUnsent { handle })
+            }
+            None => Err(ServerFlowError::Closed),
+        }
+    }
+
+    // Closes the flow and moves all responses that were not sent completely to
+    // `unsent_responses`.
+    fn close(&mut self) {
+        self.closed = true;
+        self.unsent_responses.extend(
+            self.send_response_state
+                .drain()
+                .into_iter()
+                // Internally created responses are not reported
+                .filter_map(|(handle, response)| Some((handle?, response))),
+        );
+
+        // Senders must not enqueue responses anymore.
+        let mut inner = self.sender_queue.inner.lock().unwrap();
+        inner.closed = true;
+        self.unsent_responses.extend(
+            inner
+                .responses
+                .drain(..)
+                .map(|(handle, response)| (handle, SendResponse::Message(response))),
+        );
+    }
+
+    async fn progress_open(&mut self) -> Result<ServerFlowEvent, ServerFlowError> {
         // The server must do two things:
         // - Sending responses to the client.
         // - Receiving commands from the client.
@@ -155,118 +554,238 @@ impl ServerFlow {
         //
         // Therefore we prefer the second approach and begin with sending the responses.
         loop {
+            self.take_sender_responses();
+
             if let Some(event) = self.progress_send().await? {
                 return Ok(event);
             }
 
-            if let Some(event) = self.progress_receive().await? {
-                return Ok(event);
+            if self.bye_sent {
+                // All responses were sent after the `BYE`, so we don't receive commands anymore.
+                self.close();
+                return self.progress_closed();
+            }
+
+            // Responses enqueued via `ServerFlowSender` must be sent while we are waiting for the
+            // next command. Receiving is cancel safe, so we can just interrupt it.
+            let sender_queue = self.sender_queue.clone();
+            tokio::select! {
+                result = self.progress_receive() => {
+                    if let Some(event) = result? {
+                        return Ok(event);
+                    }
+                }
+                () = sender_queue.notify.notified() => {}
             }
         }
     }
 
-    async fn progress_send(&mut self) -> Result<Option<ServerFlowEvent>, ServerFlowError> {
-        match self.send_response_state.progress(&mut self.stream).await? {
-            Some((Some(handle), response)) => {
-                // A response was sucessfully sent, inform the caller
-                Ok(Some(ServerFlowEvent::ResponseSent { handle, response }))
+    // Moves the responses enqueued via `ServerFlowSender` to the send queue.
+    fn take_sender_responses(&mut self) {
+        let responses = std::mem::take(&mut self.sender_queue.inner.lock().unwrap().responses);
+
+        for (handle, response) in responses {
+            self.complete_response_tag(&response);
+            self.send_response_state.enqueue(Some(handle), response);
+        }
+    }
+
+    /// Returns the tags of the received commands whose tagged status was not enqueued yet.
+    ///
+    /// The tags are in the order the commands were received. A tag that was reused by the
+    /// client, see [`ServerFlowEvent::DuplicateTagReceived`], is contained once per command.
+    /// Tagged statuses contained in responses enqueued via [`ServerFlow::enqueue_raw`] are
+    /// observed, too.
+    ///
+    /// Tagged statuses for tags that are not in flight are counted in
+    /// [`ServerFlowStatistics::unexpected_tagged_statuses`].
+    pub fn tags_in_flight(&self) -> &[Tag<'static>] {
+        &self.tags_in_flight
+    }
+
+    fn complete_response_tag(&mut self, response: &Response) {
+        if let Response::Status(Status::Tagged(Tagged { tag, .. })) = response {
+            self.complete_tag(tag);
+        }
+    }
+
+    // Removes the tag of a tagged status from `tags_in_flight`.
+    fn complete_tag(&mut self, tag: &Tag) {
+        match self
+            .tags_in_flight
+            .iter()
+            .position(|tag_in_flight| tag_in_flight == tag)
+        {
+            Some(index) => {
+                self.tags_in_flight.remove(index);
             }
-            Some((None, _)) => {
-                // An internally created response was sent, don't inform the caller
-                Ok(None)
+            None => {
+                warn!(?tag, "Tagged status for a tag that is not in flight");
+                self.statistics.unexpected_tagged_statuses += 1;
             }
-            _ => {
+        }
+    }
+
+    async fn progress_send(&mut self) -> Result<Option<ServerFlowEvent>, ServerFlowError> {
+        loop {
+            let Some((handle, response)) =
+                self.send_response_state.progress(&mut self.stream).await?
+            else {
                 // No progress yet
-                Ok(None)
+                return Ok(None);
+            };
+
+            let event = match response {
+                SendResponse::Message(response) => {
+                    match &response {
+                        Response::Status(_) => self.statistics.status_sent += 1,
+                        Response::Data(_) => self.statistics.data_sent += 1,
+                        Response::CommandContinuationRequest(_) => {
+                            self.statistics.continuations_sent += 1
+                        }
+                    }
+
+                    match &response {
+                        Response::Status(Status::Bye(_)) => {
+                            // The client is informed that we are going to close the connection.
+                            // The remaining responses are sent before, e.g., the tagged status
+                            // of `LOGOUT`.
+                            self.bye_sent = true;
+                        }
+                        Response::Data(Data::Enabled { capabilities }) => {
+                            // The client is informed that the extensions are enabled.
+                            self.enabled.update(capabilities);
+                        }
+                        _ => {}
+                    }
+
+                    handle.map(|handle| ServerFlowEvent::ResponseSent { handle, response })
+                }
+                SendResponse::Raw(bytes) => {
+                    // The flow doesn't inspect raw bytes.
+                    self.statistics.raw_responses_sent += 1;
+                    handle.map(|handle| ServerFlowEvent::RawResponseSent { handle, bytes })
+                }
+                SendResponse::Streamed(_) => {
+                    self.statistics.streamed_responses_sent += 1;
+                    handle.map(|handle| ServerFlowEvent::StreamedResponseSent { handle })
+                }
+            };
+
+            match event {
+                Some(event) => {
+                    // A response was sucessfully sent, inform the caller
+                    return Ok(Some(event));
+                }
+                None => {
+                    // An internally created response was sent, don't inform the caller but
+                    // continue with the remaining responses.
+                    continue;
+                }
             }
         }
     }
 
     async fn progress_receive(&mut self) -> Result<Option<ServerFlowEvent>, ServerFlowError> {
+        if self.pending_literal.is_some() {
+            return Err(ServerFlowError::LiteralDecisionPending);
+        }
+
+        self.apply_pending_options();
+
         match &mut self.receive_command_state {
             ServerReceiveState::Command(state) => {
                 match state.progress(&mut self.stream).await? {
                     ReceiveEvent::DecodingSuccess(command) => {
                         state.finish_message();
-
-                        match command.body {
-                            CommandBody::Authenticate {
-                                mechanism,
-                                initial_response,
-                            } => {
-                                self.next_expected_message = NextExpectedMessage::AuthenticateData;
-
-                                self.receive_command_state
-                                    .change_state(self.next_expected_message);
-
-                                Ok(Some(ServerFlowEvent::CommandAuthenticateReceived {
-                                    command_authenticate: CommandAuthenticate {
-                                        tag: command.tag,
-                                        mechanism,
-                                        initial_response,
-                                    },
-                                }))
-                            }
-                            body => Ok(Some(ServerFlowEvent::CommandReceived {
-                                command: Command {
-                                    tag: command.tag,
-                                    body,
+                        Ok(Some(self.handle_command(command)))
+                    }
+                    ReceiveEvent::DecodingSuccessBorrowed(bytes) => {
+                        // The server flow never sets a borrow filter, but the message is
+                        // still valid.
+                        match state.decode_static(&bytes) {
+                            Some(command) => Ok(Some(self.handle_command(command))),
+                            None => self.handle_malformed_command(
+                                Box::from(&bytes[..]),
+                                |discarded_bytes| ServerFlowError::MalformedMessage {
+                                    discarded_bytes,
                                 },
-                            })),
+                            ),
                         }
                     }
                     ReceiveEvent::DecodingFailure(CommandDecodeError::LiteralFound {
                         tag,
                         length,
-                        mode: _mode,
+                        mode,
                     }) => {
-                        if length > self.options.max_literal_size {
-                            let discarded_bytes = state.discard_message();
-
-                            // Inform the client that the literal was rejected.
-                            // This should never fail because the text is not Base64.
-                            let status = Status::no(
-                                Some(tag),
-                                None,
-                                self.options.literal_reject_text.clone(),
-                            )
-                            .unwrap();
-                            self.send_response_state
-                                .enqueue(None, Response::Status(status));
-
-                            Err(ServerFlowError::LiteralTooLong { discarded_bytes })
-                        } else {
-                            state.start_literal(length);
-
-                            // Inform the client that the literal was accepted.
-                            // This should never fail because the text is not Base64.
-                            let cont = CommandContinuationRequest::basic(
-                                None,
-                                self.options.literal_accept_text.clone(),
-                            )
-                            .unwrap();
-                            self.send_response_state
-                                .enqueue(None, Response::CommandContinuationRequest(cont));
-
-                            Ok(None)
-                        }
+                        let binary = state.is_literal8_announced();
+                        let index = state.started_literals();
+                        self.handle_literal_found(tag, length, mode, binary, index)
                     }
-                    ReceiveEvent::DecodingFailure(
+                    event @ (ReceiveEvent::DecodingFailure(
                         CommandDecodeError::Failed | CommandDecodeError::Incomplete,
-                    ) => {
+                    )
+                    | ReceiveEvent::Unexpected8Bit) => {
+                        // The codec only decodes an `APPEND` with a single message.
+                        if let ReceiveEvent::DecodingFailure(_) = event {
+                            match multiappend::decode(state.message()) {
+                                Some(MultiAppend::LiteralFound { tag, length, mode }) => {
+                                    let binary = state.is_literal8_announced();
+                                    let index = state.started_literals();
+                                    return self
+                                        .handle_literal_found(tag, length, mode, binary, index);
+                                }
+                                Some(MultiAppend::Complete(commands)) => {
+                                    state.finish_message();
+                                    return Ok(Some(self.handle_multiappend(commands)));
+                                }
+                                None => {}
+                            }
+                        }
+
+                        if self.options.skip_malformed_messages && !state.skip_message(false) {
+                            // The malformed command announced a literal, skip it.
+                            return Ok(None);
+                        }
+
                         let discarded_bytes = state.discard_message();
-                        Err(ServerFlowError::MalformedMessage { discarded_bytes })
+                        self.handle_malformed_command(discarded_bytes, |discarded_bytes| {
+                            ServerFlowError::MalformedMessage { discarded_bytes }
+                        })
                     }
                     ReceiveEvent::ExpectedCrlfGotLf => {
                         let discarded_bytes = state.discard_message();
-                        Err(ServerFlowError::ExpectedCrlfGotLf { discarded_bytes })
+                        self.handle_malformed_command(discarded_bytes, |discarded_bytes| {
+                            ServerFlowError::ExpectedCrlfGotLf { discarded_bytes }
+                        })
+                    }
+                    ReceiveEvent::MessageSkipped => {
+                        let discarded_bytes = state.discard_message();
+
+                        if self.skipping_rejected_command {
+                            // The client was already informed about the rejection.
+                            self.skipping_rejected_command = false;
+                            return Ok(None);
+                        }
+
+                        self.handle_malformed_command(discarded_bytes, |discarded_bytes| {
+                            ServerFlowError::MalformedMessage { discarded_bytes }
+                        })
                     }
+                    ReceiveEvent::LiteralProgress {
+                        announced,
+                        received,
+                    } => Ok(Some(ServerFlowEvent::LiteralProgress {
+                        announced,
+                        received,
+                    })),
                 }
             }
             ServerReceiveState::AuthenticateData(state) => {
                 match state.progress(&mut self.stream).await? {
                     ReceiveEvent::DecodingSuccess(authenticate_data) => {
                         state.finish_message();
+                        self.statistics.authenticate_data_received += 1;
                         Ok(Some(ServerFlowEvent::AuthenticateDataReceived {
                             authenticate_data,
                         }))
@@ -274,13 +793,63 @@ impl ServerFlow {
                     ReceiveEvent::DecodingFailure(
                         AuthenticateDataDecodeError::Failed
                         | AuthenticateDataDecodeError::Incomplete,
-                    ) => {
-                        let discarded_bytes = state.discard_message();
-                        Err(ServerFlowError::MalformedMessage { discarded_bytes })
+                    )
+                    | ReceiveEvent::Unexpected8Bit => {
+                        if !self.options.skip_malformed_messages {
+                            let discarded_bytes = state.discard_message();
+                            return Err(ServerFlowError::MalformedMessage { discarded_bytes });
+                        }
+
+                        if state.skip_message(false) {
+                            let discarded_bytes = state.discard_message();
+                            Ok(Some(ServerFlowEvent::MalformedMessageSkipped {
+                                discarded_bytes,
+                            }))
+                        } else {
+                            // The malformed authenticate data announced a literal, skip it.
+                            Ok(None)
+                        }
                     }
                     ReceiveEvent::ExpectedCrlfGotLf => {
+                        // Authenticate data has no tag, so it can't be rejected with `BAD`.
                         let discarded_bytes = state.discard_message();
-                        Err(ServerFlowError::ExpectedCrlfGotLf { discarded_bytes })
+                        let discarded_bytes = self.count_malformed_command(discarded_bytes)?;
+
+                        if self.options.skip_malformed_messages {
+                            Ok(Some(ServerFlowEvent::MalformedMessageSkipped {
+                                discarded_bytes,
+                            }))
+                        } else {
+                            Err(ServerFlowError::ExpectedCrlfGotLf { discarded_bytes })
+                        }
+                    }
+                    ReceiveEvent::MessageSkipped => {
+                        let discarded_bytes = state.discard_message();
+                        Ok(Some(ServerFlowEvent::MalformedMessageSkipped {
+                            discarded_bytes,
+                        }))
+                    }
+                    ReceiveEvent::LiteralProgress {
+                        announced,
+                        received,
+                    } => Ok(Some(ServerFlowEvent::LiteralProgress {
+                        announced,
+                        received,
+                    })),
+                    ReceiveEvent::DecodingSuccessBorrowed(bytes) => {
+                        // The server flow never sets a borrow filter, but the message is
+                        // still valid.
+                        match state.decode_static(&bytes) {
+                            Some(authenticate_data) => {
+                                self.statistics.authenticate_data_received += 1;
+                                Ok(Some(ServerFlowEvent::AuthenticateDataReceived {
+                                    authenticate_data,
+                                }))
+                            }
+                            None => Err(ServerFlowError::MalformedMessage {
+                                discarded_bytes: Box::from(&bytes[..]),
+                            }),
+                        }
                     }
                 }
             }
@@ -290,13 +859,352 @@ impl ServerFlow {
         }
     }
 
+    /// Shuts down the flow gracefully.
+    ///
+    /// All enqueued responses and the optional `bye` are sent before the write half of the
+    /// stream is shut down. The `bye` is guaranteed to be the last response. If `wait_for_close`
+    /// is `true`, commands are received until the client closes the stream. Returns all events
+    /// that occurred in the meantime together with the statistics of the flow.
+    ///
+    /// Returns [`ServerFlowShutdownError`] if the shutdown failed. It contains the events that
+    /// occurred before the failure including all responses that were not sent.
+    pub async fn shutdown(
+        mut self,
+        bye: Option<Bye<'static>>,
+        wait_for_close: bool,
+    ) -> Result<ServerFlowShutdown, ServerFlowShutdownError> {
+        let mut events = Vec::new();
+
+        // Senders must not enqueue responses after the `bye`.
+        self.sender_queue.inner.lock().unwrap().closed = true;
+        self.take_sender_responses();
+
+        let mut result = self.send_remaining_responses(bye, &mut events).await;
+
+        // Responses that were not sent before the shutdown, including the ones left by an
+        // earlier close of the flow.
+        self.close();
+        while let Ok(event) = self.progress_closed() {
+            events.push(event);
+        }
+
+        let mut closed = false;
+        if result.is_ok() {
+            result = self.close_stream(wait_for_close, &mut events).await;
+            closed = wait_for_close && result.is_ok();
+        }
+
+        let shutdown = ServerFlowShutdown {
+            events,
+            closed,
+            statistics: self.statistics(),
+        };
+
+        match result {
+            Ok(()) => Ok(shutdown),
+            Err(error) => Err(ServerFlowShutdownError { error, shutdown }),
+        }
+    }
+
+    async fn send_remaining_responses(
+        &mut self,
+        bye: Option<Bye<'static>>,
+        events: &mut Vec<ServerFlowEvent>,
+    ) -> Result<(), ServerFlowError> {
+        if let Some(bye) = bye {
+            self.enqueue_status(Status::Bye(bye))
+                .map_err(|_| ServerFlowError::Closed)?;
+        }
+
+        while !self.send_response_state.is_empty() {
+            if let Some(event) = self.progress_send().await? {
+                events.push(event);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn close_stream(
+        &mut self,
+        wait_for_close: bool,
+        events: &mut Vec<ServerFlowEvent>,
+    ) -> Result<(), ServerFlowError> {
+        self.stream.shutdown().await?;
+
+        if wait_for_close {
+            loop {
+                match self.progress_receive().await {
+                    Ok(Some(event)) => events.push(event),
+                    Ok(None) => {}
+                    Err(ServerFlowError::Stream(StreamError::Closed)) => break,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_literal_found(
+        &mut self,
+        tag: Tag<'static>,
+        length: u32,
+        mode: LiteralMode,
+        binary: bool,
+        index: usize,
+    ) -> Result<Option<ServerFlowEvent>, ServerFlowError> {
+        debug!(?tag, length, ?mode, binary, index, "Literal announced");
+
+        if binary && !self.options.binary {
+            // This should never fail because the text is not Base64.
+            let status = Status::bad(
+                Some(tag),
+                Some(Code::UnknownCte),
+                self.options.literal_reject_text.clone(),
+            )
+            .unwrap();
+            let discarded_bytes = self.reject_literal(length, mode, status);
+
+            Err(ServerFlowError::Literal8Rejected { discarded_bytes })
+        } else if length > self.options.max_literal_size {
+            // This should never fail because the text is not Base64.
+            let status =
+                Status::no(Some(tag), None, self.options.literal_reject_text.clone()).unwrap();
+            let discarded_bytes = self.reject_literal(length, mode, status);
+
+            Err(ServerFlowError::LiteralTooLong { discarded_bytes })
+        } else if self.options.announce_literals {
+            self.pending_literal = Some(PendingLiteral {
+                tag: tag.clone(),
+                length,
+                mode,
+            });
+
+            Ok(Some(ServerFlowEvent::LiteralAnnounced {
+                tag,
+                length,
+                mode,
+                binary,
+                index,
+            }))
+        } else {
+            self.accept_literal(length, mode);
+
+            Ok(None)
+        }
+    }
+
+    fn handle_command(&mut self, command: Command<'static>) -> ServerFlowEvent {
+        self.consecutive_malformed_commands = 0;
+        self.statistics.commands_received += 1;
+
+        let duplicate_tag = self.tags_in_flight.contains(&command.tag);
+        self.tags_in_flight.push(command.tag.clone());
+
+        match command.body {
+            // A duplicate tag is reported before the authentication starts.
+            CommandBody::Authenticate {
+                mechanism,
+                initial_response,
+            } if !duplicate_tag => {
+                self.next_expected_message = NextExpectedMessage::AuthenticateData;
+
+                self.receive_command_state
+                    .change_state(self.next_expected_message);
+
+                ServerFlowEvent::CommandAuthenticateReceived {
+                    command_authenticate: CommandAuthenticate {
+                        tag: command.tag,
+                        mechanism,
+                        initial_response,
+                    },
+                }
+            }
+            body => {
+                let command = Command {
+                    tag: command.tag,
+                    body,
+                };
+
+                if duplicate_tag {
+                    debug!(tag = ?command.tag, "Duplicate tag received");
+                    self.statistics.duplicate_tags_received += 1;
+                    ServerFlowEvent::DuplicateTagReceived { command }
+                } else {
+                    ServerFlowEvent::CommandReceived { command }
+                }
+            }
+        }
+    }
+
+    fn handle_multiappend(&mut self, mut commands: Vec<Command<'static>>) -> ServerFlowEvent {
+        self.consecutive_malformed_commands = 0;
+        self.statistics.commands_received += 1;
+
+        let tag = commands[0].tag.clone();
+        let duplicate_tag = self.tags_in_flight.contains(&tag);
+        self.tags_in_flight.push(tag);
+
+        if duplicate_tag {
+            let command = commands.swap_remove(0);
+            debug!(tag = ?command.tag, "Duplicate tag received");
+            self.statistics.duplicate_tags_received += 1;
+            ServerFlowEvent::DuplicateTagReceived { command }
+        } else {
+            ServerFlowEvent::MultiAppendReceived { commands }
+        }
+    }
+
+    fn handle_malformed_command(
+        &mut self,
+        discarded_bytes: Box<[u8]>,
+        into_error: fn(Box<[u8]>) -> ServerFlowError,
+    ) -> Result<Option<ServerFlowEvent>, ServerFlowError> {
+        let discarded_bytes = self.count_malformed_command(discarded_bytes)?;
+
+        if self.options.reject_malformed_commands {
+            // Inform the client that the command was rejected. Otherwise, the client would wait
+            // for the tagged response indefinitely.
+            // This should never fail because the text is not Base64.
+            let status = Status::bad(
+                extract_tag(&discarded_bytes),
+                None,
+                self.options.malformed_command_text.clone(),
+            )
+            .unwrap();
+            self.send_response_state
+                .enqueue(None, Response::Status(status));
+        }
+
+        if self.options.skip_malformed_messages {
+            Ok(Some(ServerFlowEvent::MalformedMessageSkipped {
+                discarded_bytes,
+            }))
+        } else {
+            Err(into_error(discarded_bytes))
+        }
+    }
+
+    // Returns the discarded bytes if the limit of consecutive malformed commands isn't exceeded.
+    fn count_malformed_command(
+        &mut self,
+        discarded_bytes: Box<[u8]>,
+    ) -> Result<Box<[u8]>, ServerFlowError> {
+        self.consecutive_malformed_commands = self.consecutive_malformed_commands.saturating_add(1);
+
+        if let Some(max) = self.options.max_consecutive_malformed_commands {
+            if self.consecutive_malformed_commands > max {
+                if self.consecutive_malformed_commands - 1 == max {
+                    // Inform the client that we are going to close the connection.
+                    // This should never fail because the text is not Base64.
+                    let bye =
+                        Status::bye(None, self.options.too_many_malformed_commands_text.clone())
+                            .unwrap();
+                    self.send_response_state
+                        .enqueue(None, Response::Status(bye));
+                }
+
+                return Err(ServerFlowError::TooManyMalformedCommands { discarded_bytes });
+            }
+        }
+
+        Ok(discarded_bytes)
+    }
+
+    /// Accepts the literal announced via [`ServerFlowEvent::LiteralAnnounced`].
+    ///
+    /// A continuation request is sent to the client if the literal is synchronizing.
+    pub fn literal_accept(&mut self) -> Result<(), ()> {
+        let PendingLiteral { length, mode, .. } = self.pending_literal.take().ok_or(())?;
+        self.accept_literal(length, mode);
+
+        Ok(())
+    }
+
+    /// Rejects the literal announced via [`ServerFlowEvent::LiteralAnnounced`].
+    ///
+    /// The command is rejected with a tagged `NO` using the given `code` and `text`, e.g.,
+    /// `[OVERQUOTA]` for an `APPEND` exceeding the user's quota. A non-synchronizing literal is
+    /// still sent by the client and will be skipped.
+    ///
+    /// This also aborts a command with multiple literals midway. The literals that were already
+    /// accepted are discarded together with the command.
+    ///
+    /// Returns `Err` if there is no pending literal or the `NO` response can't be created.
+    pub fn literal_reject(
+        &mut self,
+        code: Option<Code<'static>>,
+        text: Text<'static>,
+    ) -> Result<(), ()> {
+        let pending_literal = self.pending_literal.as_ref().ok_or(())?;
+        let status = Status::no(Some(pending_literal.tag.clone()), code, text).map_err(|_| ())?;
+        let (length, mode) = (pending_literal.length, pending_literal.mode);
+
+        self.pending_literal = None;
+        self.reject_literal(length, mode, status);
+
+        Ok(())
+    }
+
+    fn accept_literal(&mut self, length: u32, mode: LiteralMode) {
+        self.statistics.literals_accepted += 1;
+
+        if let ServerReceiveState::Command(state) = &mut self.receive_command_state {
+            state.start_literal(length);
+        }
+
+        if mode == LiteralMode::Sync {
+            debug!(length, "Sending continuation request for literal");
+
+            // Inform the client that the literal was accepted.
+            // This should never fail because the text is not Base64.
+            let cont =
+                CommandContinuationRequest::basic(None, self.options.literal_accept_text.clone())
+                    .unwrap();
+            self.send_response_state
+                .enqueue(None, Response::CommandContinuationRequest(cont));
+        }
+    }
+
+    // Returns the discarded bytes of the rejected command.
+    fn reject_literal(
+        &mut self,
+        length: u32,
+        mode: LiteralMode,
+        status: Status<'static>,
+    ) -> Box<[u8]> {
+        debug!(length, ?mode, "Rejecting literal");
+        self.statistics.literals_rejected += 1;
+
+        let discarded_bytes = match &mut self.receive_command_state {
+            ServerReceiveState::Command(state) => {
+                let discarded_bytes = state.discard_message();
+
+                if mode == LiteralMode::NonSync {
+                    // The client sends the literal anyway, so we must skip it.
+                    state.skip_literal(length, false);
+                    self.skipping_rejected_command = true;
+                }
+
+                discarded_bytes
+            }
+            _ => Box::default(),
+        };
+
+        // Inform the client that the literal was rejected.
+        self.send_response_state
+            .enqueue(None, Response::Status(status));
+
+        discarded_bytes
+    }
+
     pub fn authenticate_continue(
         &mut self,
         continuation: CommandContinuationRequest<'static>,
     ) -> Result<ServerFlowResponseHandle, ()> {
         if let ServerReceiveState::AuthenticateData { .. } = self.receive_command_state {
-            let handle = self.enqueue_continuation(continuation);
-            Ok(handle)
+            self.enqueue_continuation(continuation).map_err(|_| ())
         } else {
             Err(())
         }
@@ -307,7 +1215,7 @@ impl ServerFlow {
         status: Status<'static>,
     ) -> Result<ServerFlowResponseHandle, ()> {
         if let ServerReceiveState::AuthenticateData(_) = &mut self.receive_command_state {
-            let handle = self.enqueue_status(status);
+            let handle = self.enqueue_status(status).map_err(|_| ())?;
             self.next_expected_message = NextExpectedMessage::Command;
 
             self.receive_command_state
@@ -320,6 +1228,209 @@ impl ServerFlow {
     }
 }
 
+/// A cloneable handle for enqueuing responses from other tasks.
+///
+/// Obtained via [`ServerFlow::sender`]. This is useful for pushing untagged responses, e.g., new
+/// messages delivered by another session, to an idling client.
+#[derive(Debug)]
+pub struct ServerFlowSender {
+    // Every sender has its own generator, so we don't need to share the one of the flow.
+    handle_generator: HandleGenerator<ServerFlowResponseHandle>,
+    queue: Weak<SenderQueue>,
+}
+
+impl ServerFlowSender {
+    /// Enqueues the [`Data`] response for being sent to the client.
+    ///
+    /// The response is sent during one of the next calls of [`ServerFlow::progress`] which
+    /// returns [`ServerFlowEvent::ResponseSent`] with the returned handle.
+    ///
+    /// Returns [`ServerFlowEnqueueError`] if the flow is closed or was dropped.
+    pub fn enqueue_data(
+        &mut self,
+        data: Data<'static>,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
+        self.enqueue_response(Response::Data(data))
+    }
+
+    /// Enqueues the [`Status`] response for being sent to the client.
+    ///
+    /// The response is sent during one of the next calls of [`ServerFlow::progress`] which
+    /// returns [`ServerFlowEvent::ResponseSent`] with the returned handle.
+    ///
+    /// Returns [`ServerFlowEnqueueError`] if the flow is closed or was dropped.
+    pub fn enqueue_status(
+        &mut self,
+        status: Status<'static>,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
+        self.enqueue_response(Response::Status(status))
+    }
+
+    fn enqueue_response(
+        &mut self,
+        response: Response<'static>,
+    ) -> Result<ServerFlowResponseHandle, ServerFlowEnqueueError> {
+        let Some(queue) = self.queue.upgrade() else {
+            return Err(ServerFlowEnqueueError { response });
+        };
+
+        let handle = {
+            let mut inner = queue.inner.lock().unwrap();
+            if inner.closed {
+                return Err(ServerFlowEnqueueError { response });
+            }
+
+            let handle = self.handle_generator.generate();
+            inner.responses.push_back((handle, response));
+            handle
+        };
+
+        // Wake up the flow. The permit is stored if the flow isn't waiting right now.
+        queue.notify.notify_one();
+
+        Ok(handle)
+    }
+}
+
+impl Clone for ServerFlowSender {
+    fn clone(&self) -> Self {
+        Self {
+            handle_generator: HANDLE_GENERATOR_GENERATOR.generate(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SenderQueue {
+    inner: Mutex<SenderQueueInner>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct SenderQueueInner {
+    closed: bool,
+    responses: VecDeque<(ServerFlowResponseHandle, Response<'static>)>,
+}
+
+// Returns the tags of the tagged statuses contained in pre-encoded responses.
+//
+// Literals are skipped, so their content isn't mistaken for a response.
+fn extract_raw_tags(mut bytes: &[u8]) -> Vec<Tag<'static>> {
+    let mut tags = Vec::new();
+    let mut response_start = true;
+
+    while let Some(lf_position) = bytes.iter().position(|byte| *byte == b'\n') {
+        let (line, remaining) = bytes.split_at(lf_position + 1);
+
+        // Untagged responses start with `*` and continuation requests with `+`.
+        if response_start && !matches!(line.first(), Some(b'*' | b'+')) {
+            tags.extend(extract_tag(line));
+        }
+
+        match find_literal_announcement(line) {
+            Some(LiteralAnnouncement { length, .. }) => {
+                // The response continues after the literal.
+                bytes = remaining.get(length as usize..).unwrap_or_default();
+                response_start = false;
+            }
+            None => {
+                bytes = remaining;
+                response_start = true;
+            }
+        }
+    }
+
+    tags
+}
+
+// Checks whether the bytes consist of one or more complete responses.
+fn is_complete_responses(mut bytes: &[u8]) -> bool {
+    let codec = ResponseCodec::default();
+
+    if bytes.is_empty() {
+        return false;
+    }
+
+    while !bytes.is_empty() {
+        match codec.decode(bytes) {
+            Ok((remaining, _)) => bytes = remaining,
+            Err(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Result of [`ServerFlow::shutdown`].
+#[derive(Debug)]
+pub struct ServerFlowShutdown {
+    /// Events that occurred during the shutdown.
+    ///
+    /// Contains [`ServerFlowEvent::ResponseUnsent`], [`ServerFlowEvent::RawResponseUnsent`], and
+    /// [`ServerFlowEvent::StreamedResponseUnsent`] for all responses that were not sent.
+    pub events: Vec<ServerFlowEvent>,
+    /// `true` if the client closed the stream.
+    ///
+    /// Only waited for if `wait_for_close` is `true`.
+    pub closed: bool,
+    /// [`ServerFlowStatistics`] after the shutdown.
+    pub statistics: ServerFlowStatistics,
+}
+
+/// Statistics of a [`ServerFlow`].
+///
+/// Returned by [`ServerFlow::statistics`]. All counters start at zero when the flow is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerFlowStatistics {
+    /// Total number of bytes read from the stream.
+    pub bytes_read: u64,
+    /// Total number of bytes written to the stream.
+    pub bytes_written: u64,
+    /// Number of commands received.
+    pub commands_received: u64,
+    /// Number of commands received with a tag that was already in flight.
+    pub duplicate_tags_received: u64,
+    /// Number of tagged statuses enqueued for a tag that was not in flight.
+    ///
+    /// See [`ServerFlow::tags_in_flight`].
+    pub unexpected_tagged_statuses: u64,
+    /// Number of [`AuthenticateData`] messages received.
+    pub authenticate_data_received: u64,
+    /// Number of [`Data`] responses sent.
+    pub data_sent: u64,
+    /// Number of [`Status`] responses sent, including the internally created ones.
+    pub status_sent: u64,
+    /// Number of [`CommandContinuationRequest`] responses sent, including the internally
+    /// created ones.
+    pub continuations_sent: u64,
+    /// Number of pre-encoded responses sent via [`ServerFlow::enqueue_raw`].
+    pub raw_responses_sent: u64,
+    /// Number of responses sent via [`ServerFlow::enqueue_streamed`].
+    pub streamed_responses_sent: u64,
+    /// Number of command literals accepted.
+    pub literals_accepted: u64,
+    /// Number of command literals rejected.
+    pub literals_rejected: u64,
+    /// Number of discarded messages, e.g., malformed commands or commands with rejected literals.
+    pub discarded_messages: u64,
+    /// Number of enqueued responses that were not sent completely yet.
+    pub send_queue_len: usize,
+    /// Number of bytes in the read buffer.
+    pub read_buffer_len: usize,
+    /// Number of bytes the read buffer can hold without allocating.
+    ///
+    /// Large allocations are released after the command was received completely.
+    pub read_buffer_capacity: usize,
+}
+
+#[derive(Debug)]
+struct PendingLiteral {
+    tag: Tag<'static>,
+    length: u32,
+    mode: LiteralMode,
+}
+
 #[derive(Debug, Clone, Copy)]
 enum NextExpectedMessage {
     Command,
@@ -335,8 +1446,46 @@ enum ServerReceiveState {
 }
 
 impl ServerReceiveState {
+    fn is_message_boundary(&self) -> bool {
+        match self {
+            ServerReceiveState::Command(state) => state.is_message_boundary(),
+            ServerReceiveState::AuthenticateData(state) => state.is_message_boundary(),
+            ServerReceiveState::Dummy => unreachable!(),
+        }
+    }
+
+    fn set_crlf_relaxed(&mut self, crlf_relaxed: bool) {
+        match self {
+            ServerReceiveState::Command(state) => state.set_crlf_relaxed(crlf_relaxed),
+            ServerReceiveState::AuthenticateData(state) => state.set_crlf_relaxed(crlf_relaxed),
+            ServerReceiveState::Dummy => unreachable!(),
+        }
+    }
+
+    fn set_utf8_accepted(&mut self, utf8_accepted: bool) {
+        match self {
+            ServerReceiveState::Command(state) => state.set_utf8_accepted(utf8_accepted),
+            ServerReceiveState::AuthenticateData(state) => state.set_utf8_accepted(utf8_accepted),
+            ServerReceiveState::Dummy => unreachable!(),
+        }
+    }
+
+    fn set_literal_progress_granularity(&mut self, granularity: Option<u32>) {
+        match self {
+            ServerReceiveState::Command(state) => {
+                state.set_literal_progress_granularity(granularity)
+            }
+            ServerReceiveState::AuthenticateData(state) => {
+                state.set_literal_progress_granularity(granularity)
+            }
+            ServerReceiveState::Dummy => unreachable!(),
+        }
+    }
+
     fn change_state(&mut self, next_expected_message: NextExpectedMessage) {
         // NOTE: This function MUST NOT panic. Otherwise the dummy state will remain indefinitely.
+        debug!(?next_expected_message, "Changing codec");
+
         let old_state = std::mem::replace(self, ServerReceiveState::Dummy);
         let new_state = match next_expected_message {
             NextExpectedMessage::Command => ServerReceiveState::Command(match old_state {
@@ -396,6 +1545,25 @@ pub enum ServerFlowEvent {
     },
     /// Command received.
     CommandReceived { command: Command<'static> },
+    /// Command received whose tag is already in flight, see [`ServerFlow::tags_in_flight`].
+    ///
+    /// Returned instead of [`ServerFlowEvent::CommandReceived`],
+    /// [`ServerFlowEvent::MultiAppendReceived`] or
+    /// [`ServerFlowEvent::CommandAuthenticateReceived`]. Clients must use unique tags, otherwise
+    /// tagged statuses are ambiguous. The application should reject the command, e.g., with a
+    /// tagged `BAD`. The tag stays in flight until a tagged status was enqueued for every
+    /// command using it.
+    ///
+    /// Note: For `AUTHENTICATE`, the authentication is not started, so
+    /// [`ServerFlow::authenticate_continue`] and [`ServerFlow::authenticate_finish`] must not be
+    /// used. For `MULTIAPPEND`, only the first message is returned.
+    DuplicateTagReceived { command: Command<'static> },
+    /// Command `APPEND` with more than one message (`MULTIAPPEND`, RFC 3502) received.
+    ///
+    /// [`CommandBody::Append`] only holds a single message, so every message is returned as an
+    /// `APPEND` with the tag and mailbox of the `MULTIAPPEND`. The messages must be appended
+    /// atomically, i.e., either all or none of them, and answered with a single tagged status.
+    MultiAppendReceived { commands: Vec<Command<'static>> },
     /// Command AUTHENTICATE received.
     ///
     /// Note: The server MUST call [`ServerFlow::authenticate_continue`] (if it needs more data for
@@ -416,6 +1584,87 @@ pub enum ServerFlowEvent {
     /// Make sure to honor the client's request to not end up in an infinite loop. It's up to the
     /// server to end the authentication flow.
     AuthenticateDataReceived { authenticate_data: AuthenticateData },
+    /// Literal announced by the client.
+    ///
+    /// Note: Only emitted when [`ServerFlowOptions::announce_literals`] is enabled. The server
+    /// MUST call [`ServerFlow::literal_accept`] or [`ServerFlow::literal_reject`] next.
+    LiteralAnnounced {
+        /// Tag of the command containing the literal.
+        tag: Tag<'static>,
+        /// Announced length of the literal.
+        length: u32,
+        /// Whether the client waits for a continuation request before sending the literal.
+        mode: LiteralMode,
+        /// Whether the literal is a literal8 (RFC 3516) that may contain NUL bytes.
+        ///
+        /// Note: Only `true` if [`ServerFlowOptions::binary`] is enabled.
+        binary: bool,
+        /// Index of the literal within the command.
+        ///
+        /// E.g., `1` for the password of a `LOGIN` whose username and password are literals.
+        /// All literals before it were already accepted.
+        ///
+        /// E.g., `1` for the second message of a `MULTIAPPEND` (RFC 3502).
+        index: usize,
+    },
+    /// Bytes of an accepted literal were received from the client.
+    ///
+    /// Note: Only emitted when [`ServerFlowOptions::literal_progress_granularity`] is set. The
+    /// command containing the literal is returned once it was received completely.
+    LiteralProgress {
+        /// Announced length of the literal.
+        announced: u32,
+        /// Number of bytes of the literal received so far.
+        received: u32,
+    },
+    /// Enqueued [`Response`] will never be sent.
+    ///
+    /// Note: Emitted for every [`Response`] that was not sent completely when the flow was
+    /// closed.
+    ResponseUnsent {
+        /// Handle of the formerly enqueued [`Response`].
+        handle: ServerFlowResponseHandle,
+        /// Formerly enqueued [`Response`] that will never be sent.
+        response: Response<'static>,
+    },
+    /// Pre-encoded response enqueued via [`ServerFlow::enqueue_raw`] was sent successfully.
+    RawResponseSent {
+        /// Handle of the formerly enqueued bytes.
+        handle: ServerFlowResponseHandle,
+        /// Formerly enqueued bytes that were now sent.
+        bytes: Bytes,
+    },
+    /// Pre-encoded response enqueued via [`ServerFlow::enqueue_raw`] will never be sent.
+    ///
+    /// Note: Emitted for every pre-encoded response that was not sent completely when the flow
+    /// was closed.
+    RawResponseUnsent {
+        /// Handle of the formerly enqueued bytes.
+        handle: ServerFlowResponseHandle,
+        /// Formerly enqueued bytes that will never be sent.
+        bytes: Bytes,
+    },
+    /// Response enqueued via [`ServerFlow::enqueue_streamed`] was sent successfully.
+    StreamedResponseSent {
+        /// Handle of the formerly enqueued response.
+        handle: ServerFlowResponseHandle,
+    },
+    /// Response enqueued via [`ServerFlow::enqueue_streamed`] will never be sent.
+    ///
+    /// Note: Emitted for every streamed response that was not sent completely when the flow
+    /// was closed.
+    StreamedResponseUnsent {
+        /// Handle of the formerly enqueued response.
+        handle: ServerFlowResponseHandle,
+    },
+    /// Malformed command skipped.
+    ///
+    /// Note: Only emitted when [`ServerFlowOptions::skip_malformed_messages`] is enabled. The
+    /// flow is still usable afterwards.
+    MalformedMessageSkipped {
+        /// Raw bytes of the skipped command (including skipped literals).
+        discarded_bytes: Box<[u8]>,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -428,4 +1677,83 @@ pub enum ServerFlowError {
     MalformedMessage { discarded_bytes: Box<[u8]> },
     #[error("Literal was rejected because it was too long")]
     LiteralTooLong { discarded_bytes: Box<[u8]> },
+    /// A literal8 was received although [`ServerFlowOptions::binary`] is disabled.
+    ///
+    /// Note: The command was rejected with `BAD [UNKNOWN-CTE]`.
+    #[error("Literal8 was rejected because BINARY is not enabled")]
+    Literal8Rejected { discarded_bytes: Box<[u8]> },
+    /// Too many consecutive malformed commands were received.
+    ///
+    /// Note: A `BYE` was enqueued and will be sent during the next call of
+    /// [`ServerFlow::progress`]. The connection should be closed afterwards.
+    #[error("Received too many consecutive malformed messages")]
+    TooManyMalformedCommands { discarded_bytes: Box<[u8]> },
+    /// [`ServerFlow::literal_accept`] or [`ServerFlow::literal_reject`] wasn't called after
+    /// [`ServerFlowEvent::LiteralAnnounced`].
+    #[error("Literal must be accepted or rejected first")]
+    LiteralDecisionPending,
+    /// Reading the literal of a response enqueued via [`ServerFlow::enqueue_streamed`] failed.
+    ///
+    /// Note: The flow is closed because the response can't be completed.
+    #[error("Failed to read literal")]
+    LiteralReader(#[source] std::io::Error),
+    /// The flow is closed and can't be used anymore.
+    #[error("Flow is closed")]
+    Closed,
+}
+
+impl From<SendError> for ServerFlowError {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::Stream(error) => Self::Stream(error),
+            SendError::LiteralReader(error) => Self::LiteralReader(error),
+        }
+    }
+}
+
+/// Error returned by [`ServerFlow::shutdown`].
+#[derive(Debug, Error)]
+#[error("Failed to shut down flow")]
+pub struct ServerFlowShutdownError {
+    /// The error that stopped the shutdown.
+    #[source]
+    pub error: ServerFlowError,
+    /// Events that occurred before the error together with the statistics.
+    pub shutdown: ServerFlowShutdown,
+}
+
+/// Error returned by [`ServerFlow::enqueue_data`], [`ServerFlow::enqueue_status`],
+/// [`ServerFlow::enqueue_continuation`], and the methods of [`ServerFlowSender`].
+#[derive(Debug, Error)]
+#[error("Flow is closed")]
+pub struct ServerFlowEnqueueError {
+    /// The [`Response`] that was not enqueued.
+    pub response: Response<'static>,
+}
+
+/// Error returned by [`ServerFlow::enqueue_raw`].
+#[derive(Debug, Error)]
+#[error("Flow is closed")]
+pub struct ServerFlowEnqueueRawError {
+    /// The bytes that were not enqueued.
+    pub bytes: Bytes,
+}
+
+/// Error returned by [`ServerFlow::enqueue_streamed`].
+#[derive(Debug, Error)]
+#[error("Flow is closed")]
+pub struct ServerFlowEnqueueStreamedError {
+    pub head: Bytes,
+    pub literal: LiteralReader,
+    pub tail: Bytes,
+}
+
+/// Extracts the tag of a malformed command on a best-effort basis.
+fn extract_tag(discarded_bytes: &[u8]) -> Option<Tag<'static>> {
+    let end = discarded_bytes
+        .iter()
+        .position(|byte| matches!(byte, b' ' | b'\r' | b'\n'))?;
+    let tag = std::str::from_utf8(&discarded_bytes[..end]).ok()?;
+
+    Tag::try_from(tag.to_owned()).ok()
 }
@@ -4,6 +4,8 @@ use bytes::BytesMut;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::buffer;
+
 // TODO: Reconsider this. Do we really need Stream + AnyStream? What is the smallest API that we need to expose?
 
 pub trait Stream: AsyncRead + AsyncWrite + Send + Debug {}
@@ -11,18 +13,61 @@ pub trait Stream: AsyncRead + AsyncWrite + Send + Debug {}
 impl<S: AsyncRead + AsyncWrite + Send + Debug> Stream for S {}
 
 #[derive(Debug)]
-pub struct AnyStream(pub Pin<Box<dyn Stream>>);
+pub struct AnyStream {
+    stream: Pin<Box<dyn Stream>>,
+    // Total number of bytes read from the stream.
+    bytes_read: u64,
+    // Total number of bytes written to the stream.
+    bytes_written: u64,
+}
 
 impl AnyStream {
     pub fn new<S: Stream + 'static>(stream: S) -> Self {
-        Self(Box::pin(stream))
+        Self {
+            stream: Box::pin(stream),
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &Pin<Box<dyn Stream>> {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    ///
+    /// Note: Bytes read from or written to the underlying stream directly are not counted by
+    /// [`AnyStream::bytes_read`] and [`AnyStream::bytes_written`].
+    pub fn get_mut(&mut self) -> &mut Pin<Box<dyn Stream>> {
+        &mut self.stream
+    }
+
+    /// Consumes the [`AnyStream`] and returns the underlying stream.
+    pub fn into_inner(self) -> Pin<Box<dyn Stream>> {
+        self.stream
+    }
+
+    /// Returns the total number of bytes read from the stream.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Returns the total number of bytes written to the stream.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
     }
 
     /// Reads at least one byte into the buffer and returns the number of read bytes.
     ///
     /// Returns [`StreamError::Closed`] when no bytes could be read.
+    ///
+    /// Note: This method is cancel safe, no bytes are read if the future is dropped before
+    /// completion.
     pub async fn read(&mut self, read_buffer: &mut BytesMut) -> Result<NonZeroUsize, StreamError> {
-        let byte_count = self.0.read_buf(read_buffer).await?;
+        let byte_count = self.stream.read_buf(read_buffer).await?;
+        trace!(byte_count, "Read bytes");
+        self.bytes_read += byte_count as u64;
 
         match NonZeroUsize::new(byte_count) {
             None => {
@@ -38,9 +83,19 @@ impl AnyStream {
     /// Writes all bytes from the write buffer.
     ///
     /// Returns [`StreamError::Closed`] when not all bytes could be written.
+    ///
+    /// A large allocation of the write buffer is released after all bytes were written.
+    ///
+    /// Note: This method is cancel safe. Written bytes are removed from the write buffer
+    /// immediately, so a subsequent call continues with the remaining bytes.
     pub async fn write_all(&mut self, write_buffer: &mut BytesMut) -> Result<(), StreamError> {
+        let mut written_bytes = 0;
+
         while !write_buffer.is_empty() {
-            let byte_count = self.0.write_buf(write_buffer).await?;
+            let byte_count = self.stream.write_buf(write_buffer).await?;
+            trace!(byte_count, "Wrote bytes");
+            self.bytes_written += byte_count as u64;
+            written_bytes += byte_count;
 
             if byte_count == 0 {
                 // The result is 0 if the stream doesn't accept bytes anymore or the write buffer
@@ -50,6 +105,17 @@ impl AnyStream {
             }
         }
 
+        buffer::shrink(write_buffer, written_bytes);
+
+        Ok(())
+    }
+
+    /// Shuts down the write half of the stream.
+    ///
+    /// Depending on the stream implementation, this also sends a TLS `close_notify`.
+    pub async fn shutdown(&mut self) -> Result<(), StreamError> {
+        self.stream.shutdown().await?;
+
         Ok(())
     }
 }
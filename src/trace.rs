@@ -0,0 +1,60 @@
+//! Internal macros for the optional `tracing` instrumentation.
+//!
+//! Without the `tracing` feature the macros expand to nothing (or the plain future).
+
+// Emits a `tracing` event with level `TRACE`.
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        tracing::trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+// Emits a `tracing` event with level `DEBUG`.
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+
+// Emits a `tracing` event with level `WARN`.
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        tracing::warn!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
+
+// Instruments the future with the span of the flow.
+//
+// Note: The span is evaluated first because the future usually borrows the flow mutably.
+#[cfg(feature = "tracing")]
+macro_rules! instrument {
+    ($future:expr, $span:expr) => {{
+        let span = $span.clone();
+        tracing::Instrument::instrument($future, span)
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! instrument {
+    ($future:expr, $span:expr) => {
+        $future
+    };
+}
@@ -1,11 +1,25 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Formatter},
+    num::NonZeroU32,
+    ops::RangeInclusive,
+    pin::Pin,
+};
 
 use imap_codec::imap_types::{
     auth::AuthMechanism,
     command::{Command, CommandBody},
     core::Tag,
+    datetime::DateTime,
+    error::ValidationError,
+    extensions::enable::CapabilityEnable,
+    flag::Flag,
+    mailbox::Mailbox,
     secret::Secret,
 };
+use tokio::io::AsyncRead;
+
+use crate::utf7;
 
 #[derive(Debug)]
 pub struct CommandAuthenticate {
@@ -25,3 +39,168 @@ impl From<CommandAuthenticate> for Command<'static> {
         }
     }
 }
+
+/// `APPEND` whose messages are read from [`LiteralReader`]s.
+///
+/// More than one message requires `MULTIAPPEND` (RFC 3502).
+#[derive(Debug)]
+pub struct CommandAppend {
+    pub tag: Tag<'static>,
+    pub mailbox: Mailbox<'static>,
+    pub messages: Vec<AppendMessage>,
+}
+
+/// Message of a [`CommandAppend`].
+#[derive(Debug)]
+pub struct AppendMessage {
+    pub flags: Vec<Flag<'static>>,
+    pub date: Option<DateTime>,
+    /// Send the message as literal8 (RFC 3516), e.g., when it contains NUL bytes.
+    ///
+    /// Note: Requires the `BINARY` capability.
+    pub binary: bool,
+    pub literal: LiteralReader,
+}
+
+/// Extensions enabled via `ENABLE` (RFC 5161).
+///
+/// Mailbox names must be converted with [`EnabledExtensions::mailbox`] and
+/// [`EnabledExtensions::mailbox_name`]. These use modified UTF-7 until UTF-8 is enabled.
+///
+/// Both flows only accept 8-bit characters outside of literals, e.g., UTF-8 in quoted strings,
+/// after UTF-8 was enabled. The codecs themselves have no UTF-8 mode, so strings with non-ASCII
+/// characters are always sent as literal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnabledExtensions {
+    /// `UTF8=ACCEPT` (RFC 6855) is enabled.
+    pub utf8_accept: bool,
+    /// `IMAP4rev2` (RFC 9051) is enabled.
+    pub imap4rev2: bool,
+}
+
+impl EnabledExtensions {
+    /// Marks the capabilities of an `ENABLED` response as enabled.
+    pub(crate) fn update(&mut self, capabilities: &[CapabilityEnable]) {
+        for capability in capabilities {
+            match capability {
+                CapabilityEnable::Utf8(_) => self.utf8_accept = true,
+                CapabilityEnable::Other(other)
+                    if other.inner().as_ref().eq_ignore_ascii_case("IMAP4rev2") =>
+                {
+                    // IMAP4rev2 requires UTF-8 support, see RFC 9051, section 6.3.1.
+                    self.imap4rev2 = true;
+                    self.utf8_accept = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Creates a [`Mailbox`] from a mailbox name.
+    ///
+    /// The name is encoded in modified UTF-7 (RFC 3501, section 5.1.3). After `UTF8=ACCEPT` or
+    /// `IMAP4rev2` was enabled, it is used as is (RFC 6855, section 3).
+    pub fn mailbox(&self, name: &str) -> Result<Mailbox<'static>, ValidationError> {
+        if self.utf8_accept {
+            Mailbox::try_from(name.to_owned())
+        } else {
+            Mailbox::try_from(utf7::encode(name))
+        }
+    }
+
+    /// Returns the name of a received [`Mailbox`].
+    ///
+    /// The name is decoded from modified UTF-7 unless UTF-8 is enabled, see
+    /// [`EnabledExtensions::mailbox`]. Returns `None` if the name is invalid, e.g., if it contains
+    /// non-ASCII characters before UTF-8 was enabled.
+    pub fn mailbox_name(&self, mailbox: &Mailbox) -> Option<String> {
+        let name = match mailbox {
+            Mailbox::Inbox => return Some(String::from("INBOX")),
+            Mailbox::Other(other) => std::str::from_utf8(other.as_ref()).ok()?,
+        };
+
+        if self.utf8_accept {
+            Some(name.to_owned())
+        } else {
+            utf7::decode(name)
+        }
+    }
+
+    /// Returns the [`ProtocolVersion`] implied by the enabled extensions.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        if self.imap4rev2 {
+            ProtocolVersion::Imap4rev2
+        } else {
+            ProtocolVersion::Imap4rev1
+        }
+    }
+}
+
+/// The IMAP protocol version used by a flow.
+///
+/// In [`ProtocolVersion::Imap4rev2`] mode, [`ClientFlow`](crate::client::ClientFlow) accepts
+/// `ESEARCH` responses and counts `RECENT` responses as unexpected. `LIST-STATUS` needs no
+/// special handling because its `STATUS` responses are regular [`Data`] responses.
+///
+/// [`Data`]: imap_codec::imap_types::response::Data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// IMAP4rev1 (RFC 3501).
+    #[default]
+    Imap4rev1,
+    /// IMAP4rev2 (RFC 9051).
+    Imap4rev2,
+}
+
+/// An `ESEARCH` response (RFC 4731), which replaces `SEARCH` in IMAP4rev2.
+///
+/// Only the return data defined by RFC 9051 is kept, other return data is ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ESearchResponse {
+    /// Tag of the `SEARCH` command the response belongs to.
+    pub tag: Option<Tag<'static>>,
+    /// The numbers are UIDs instead of message sequence numbers.
+    pub uid: bool,
+    /// Lowest matching number (`MIN`).
+    pub min: Option<NonZeroU32>,
+    /// Highest matching number (`MAX`).
+    pub max: Option<NonZeroU32>,
+    /// All matching numbers (`ALL`).
+    pub all: Option<Vec<RangeInclusive<NonZeroU32>>>,
+    /// Number of matching messages (`COUNT`).
+    pub count: Option<u32>,
+}
+
+/// Literal whose bytes are read from an [`AsyncRead`] while they are sent.
+///
+/// This avoids loading large literals, e.g., message bodies, into memory. The bytes are copied
+/// to the stream in chunks.
+///
+/// Note: The reader must provide exactly `length` bytes. The flow reports an error if the reader
+/// fails or ends early because the literal can't be completed anymore.
+pub struct LiteralReader {
+    length: u32,
+    pub(crate) reader: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+impl LiteralReader {
+    pub fn new<R: AsyncRead + Send + 'static>(length: u32, reader: R) -> Self {
+        Self {
+            length,
+            reader: Box::pin(reader),
+        }
+    }
+
+    /// Returns the length of the literal.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+}
+
+impl Debug for LiteralReader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LiteralReader")
+            .field("length", &self.length)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,108 @@
+//! Modified UTF-7 for mailbox names, see RFC 3501, section 5.1.3.
+//!
+//! Printable ASCII characters represent themselves, except `&` which is written as `&-`. All
+//! other characters are written as UTF-16 in a modified Base64 (`,` instead of `/`, no padding)
+//! between `&` and `-`.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Encodes the mailbox name in modified UTF-7.
+pub(crate) fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    let mut units = Vec::new();
+
+    for char in name.chars() {
+        if is_printable(char) {
+            flush(&mut units, &mut encoded);
+
+            match char {
+                '&' => encoded.push_str("&-"),
+                char => encoded.push(char),
+            }
+        } else {
+            let mut buffer = [0; 2];
+            units.extend_from_slice(char.encode_utf16(&mut buffer));
+        }
+    }
+
+    flush(&mut units, &mut encoded);
+
+    encoded
+}
+
+/// Decodes the mailbox name from modified UTF-7.
+///
+/// Returns `None` if the name is not valid modified UTF-7.
+pub(crate) fn decode(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut chars = name.chars();
+
+    while let Some(char) = chars.next() {
+        if !is_printable(char) {
+            return None;
+        }
+
+        if char != '&' {
+            decoded.push(char);
+            continue;
+        }
+
+        // Collect the Base64 section up to the `-`.
+        let mut bits = 0u32;
+        let mut bit_count = 0;
+        let mut units = Vec::new();
+        loop {
+            let byte = match chars.next()? {
+                '-' => break,
+                char => u8::try_from(char).ok()?,
+            };
+            let value = ALPHABET.iter().position(|b| *b == byte)? as u32;
+
+            bits = (bits << 6) | value;
+            bit_count += 6;
+            if bit_count >= 16 {
+                bit_count -= 16;
+                units.push((bits >> bit_count) as u16);
+                bits &= (1 << bit_count) - 1;
+            }
+        }
+
+        if units.is_empty() {
+            // `&-` is the encoded `&`.
+            if bit_count != 0 {
+                return None;
+            }
+            decoded.push('&');
+        } else {
+            decoded.push_str(&String::from_utf16(&units).ok()?);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn is_printable(char: char) -> bool {
+    matches!(char, '\x20'..='\x7e')
+}
+
+// Appends the collected UTF-16 code units as a Base64 section.
+fn flush(units: &mut Vec<u16>, encoded: &mut String) {
+    if units.is_empty() {
+        return;
+    }
+
+    let bytes: Vec<u8> = units.drain(..).flat_map(u16::to_be_bytes).collect();
+
+    encoded.push('&');
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+            group | (u32::from(*byte) << (16 - 8 * index))
+        });
+
+        // 1, 2, or 3 bytes result in 2, 3, or 4 characters.
+        for index in 0..=chunk.len() {
+            encoded.push(ALPHABET[((group >> (18 - 6 * index)) & 0x3f) as usize] as char);
+        }
+    }
+    encoded.push('-');
+}
@@ -23,7 +23,9 @@ async fn main() {
         Scheduler::new(flow)
     };
 
-    let handle1 = scheduler.enqueue_task(CapabilityTask::default());
+    let Ok(handle1) = scheduler.enqueue_task(CapabilityTask::default()) else {
+        panic!("scheduler doesn't accept tasks anymore");
+    };
 
     loop {
         match scheduler.progress().await.unwrap() {
@@ -43,8 +45,13 @@ async fn main() {
         }
     }
 
-    let handle2 = scheduler.enqueue_task(AuthenticatePlainTask::new("alice", "pa²²w0rd", true));
-    let handle3 = scheduler.enqueue_task(LogoutTask::default());
+    let Ok(handle2) = scheduler.enqueue_task(AuthenticatePlainTask::new("alice", "pa²²w0rd", true))
+    else {
+        panic!("scheduler doesn't accept tasks anymore");
+    };
+    let Ok(handle3) = scheduler.enqueue_task(LogoutTask::default()) else {
+        panic!("scheduler doesn't accept tasks anymore");
+    };
 
     loop {
         match scheduler.progress().await.unwrap() {
@@ -11,7 +11,10 @@ use imap_codec::imap_types::{
     core::Tag,
     response::{Bye, CommandContinuationRequest, Data, Response, Status, StatusBody, Tagged},
 };
-use imap_flow::client::{ClientFlow, ClientFlowCommandHandle, ClientFlowError, ClientFlowEvent};
+use imap_flow::{
+    client::{ClientFlow, ClientFlowCommandHandle, ClientFlowError, ClientFlowEvent},
+    types::{ESearchResponse, ProtocolVersion},
+};
 use tag_generator::TagGenerator;
 use thiserror::Error;
 
@@ -34,12 +37,29 @@ pub trait Task: 'static {
     /// Note: The [`Scheduler`] will tag the [`CommandBody`] creating a complete [`Command`].
     fn command_body(&self) -> CommandBody<'static>;
 
+    /// Returns the [`CommandBody`] to issue for this task when IMAP4rev2 is used.
+    ///
+    /// Note: The [`Scheduler`] chooses between [`Self::command_body`] and this method based on
+    /// [`ClientFlow::protocol_version`].
+    fn command_body_rev2(&self) -> CommandBody<'static> {
+        // Default: Same command as in IMAP4rev1
+        self.command_body()
+    }
+
     /// Process data response.
     fn process_data(&mut self, data: Data<'static>) -> Option<Data<'static>> {
         // Default: Don't process server data
         Some(data)
     }
 
+    /// Process `ESEARCH` response (IMAP4rev2).
+    ///
+    /// Note: The [`Scheduler`] only passes a response with a tag to the task with this tag.
+    fn process_esearch(&mut self, esearch: ESearchResponse) -> Option<ESearchResponse> {
+        // Default: Don't process `ESEARCH`
+        Some(esearch)
+    }
+
     /// Process untagged response.
     fn process_untagged(
         &mut self,
@@ -99,31 +119,41 @@ impl Scheduler {
     }
 
     /// Enqueue a [`Task`].
-    pub fn enqueue_task<T>(&mut self, task: T) -> TaskHandle<T>
+    ///
+    /// Gives the task back if the flow doesn't send commands anymore, e.g., after the server
+    /// sent `BYE`.
+    pub fn enqueue_task<T>(&mut self, task: T) -> Result<TaskHandle<T>, T>
     where
         T: Task,
     {
         let tag = self.tag_generator.generate();
 
         let cmd = {
-            let body = task.command_body();
+            let body = match self.flow.protocol_version() {
+                ProtocolVersion::Imap4rev1 => task.command_body(),
+                ProtocolVersion::Imap4rev2 => task.command_body_rev2(),
+            };
             Command {
                 tag: tag.clone(),
                 body,
             }
         };
 
-        let handle = self.flow.enqueue_command(cmd);
+        let Ok(handle) = self.flow.enqueue_command(cmd) else {
+            return Err(task);
+        };
 
         self.waiting_tasks.push_back(handle, tag, Box::new(task));
 
-        TaskHandle::new(handle)
+        Ok(TaskHandle::new(handle))
     }
 
     /// Progress the connection returning the next event.
     pub async fn progress(&mut self) -> Result<SchedulerEvent, SchedulerError> {
         loop {
             let event = self.flow.progress().await?;
+            // Tasks process owned data, copy it if `ClientFlowOptions::borrow_data` is enabled.
+            let event = self.flow.to_owned_event(event);
 
             match event {
                 ClientFlowEvent::CommandSent { handle, .. } => {
@@ -131,6 +161,18 @@ impl Scheduler {
                     let (handle, tag, task) = self.waiting_tasks.remove_by_handle(handle).unwrap();
                     self.active_tasks.push_back(handle, tag, task);
                 }
+                ClientFlowEvent::CommandUnsent { handle, .. } => {
+                    // The command will never be sent, forget the task.
+                    if self.waiting_tasks.remove_by_handle(handle).is_none() {
+                        self.active_tasks.remove_by_handle(handle);
+                    }
+                }
+                ClientFlowEvent::MalformedMessageSkipped { .. } => {
+                    // Nothing to do, the flow already skipped the malformed response.
+                }
+                ClientFlowEvent::LiteralProgress { .. } => {
+                    // Nothing to do, tasks only process complete responses.
+                }
                 ClientFlowEvent::CommandRejected { handle, status, .. } => {
                     let body = match status {
                         Status::Tagged(Tagged { body, .. }) => body,
@@ -186,6 +228,20 @@ impl Scheduler {
 
                     return Ok(SchedulerEvent::TaskFinished(TaskToken { handle, output }));
                 }
+                ClientFlowEvent::DataAvailable => {
+                    // Nothing to do, the data was already converted by
+                    // `ClientFlow::to_owned_event`.
+                }
+                ClientFlowEvent::AppendProgress { .. }
+                | ClientFlowEvent::AppendSent { .. }
+                | ClientFlowEvent::AppendUnsent { .. } => {
+                    // Nothing to do, the scheduler only enqueues commands via
+                    // `ClientFlow::enqueue_command`.
+                }
+                ClientFlowEvent::AppendRejected { status, .. } => {
+                    // Not expected for the same reason, but don't hide the status.
+                    return Ok(SchedulerEvent::Unsolicited(Response::Status(status)));
+                }
                 ClientFlowEvent::DataReceived { data } => {
                     if let Some(data) =
                         trickle_down(data, self.active_tasks.tasks_mut(), |task, data| {
@@ -195,6 +251,24 @@ impl Scheduler {
                         return Ok(SchedulerEvent::Unsolicited(Response::Data(data)));
                     }
                 }
+                ClientFlowEvent::ESearchReceived { esearch } => {
+                    let esearch = match esearch
+                        .tag
+                        .as_ref()
+                        .and_then(|tag| self.active_tasks.get_task_by_tag_mut(tag))
+                    {
+                        Some(task) => task.process_esearch(esearch),
+                        None => {
+                            trickle_down(esearch, self.active_tasks.tasks_mut(), |task, esearch| {
+                                task.process_esearch(esearch)
+                            })
+                        }
+                    };
+
+                    if let Some(esearch) = esearch {
+                        return Ok(SchedulerEvent::UnsolicitedESearch(esearch));
+                    }
+                }
                 ClientFlowEvent::ContinuationReceived { continuation } => {
                     if let Some(continuation) = trickle_down(
                         continuation,
@@ -271,6 +345,12 @@ impl TaskMap {
             .find_map(|(current_handle, _, task)| (handle == *current_handle).then_some(task))
     }
 
+    fn get_task_by_tag_mut(&mut self, tag: &Tag) -> Option<&mut Box<dyn TaskAny>> {
+        self.tasks
+            .iter_mut()
+            .find_map(|(_, current_tag, task)| (tag == current_tag).then_some(task))
+    }
+
     fn tasks_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn TaskAny>> {
         self.tasks.iter_mut().map(|(_, _, task)| task)
     }
@@ -302,6 +382,10 @@ impl TaskMap {
 pub enum SchedulerEvent {
     TaskFinished(TaskToken),
     Unsolicited(Response<'static>),
+    /// `ESEARCH` response no task was interested in.
+    ///
+    /// imap-codec doesn't decode `ESEARCH`, so it can't be a [`Response`].
+    UnsolicitedESearch(ESearchResponse),
 }
 
 #[derive(Debug, Error)]
@@ -413,6 +497,8 @@ where
 trait TaskAny {
     fn process_data(&mut self, data: Data<'static>) -> Option<Data<'static>>;
 
+    fn process_esearch(&mut self, esearch: ESearchResponse) -> Option<ESearchResponse>;
+
     fn process_untagged(&mut self, status_body: StatusBody<'static>)
         -> Option<StatusBody<'static>>;
 
@@ -439,6 +525,10 @@ where
         T::process_data(self, data)
     }
 
+    fn process_esearch(&mut self, esearch: ESearchResponse) -> Option<ESearchResponse> {
+        T::process_esearch(self, esearch)
+    }
+
     fn process_untagged(
         &mut self,
         status_body: StatusBody<'static>,
@@ -2,15 +2,17 @@
 //!
 //! The tasks here correspond to the invocation (and processing) of a single command.
 
-use std::borrow::Cow;
+use std::{borrow::Cow, num::NonZeroU32};
 
 use imap_codec::imap_types::{
     auth::{AuthMechanism, AuthenticateData},
     command::CommandBody,
     core::NonEmptyVec,
     response::{Bye, Capability, CommandContinuationRequest, Data, StatusBody, StatusKind},
+    search::SearchKey,
     secret::Secret,
 };
+use imap_flow::types::ESearchResponse;
 
 use crate::Task;
 
@@ -78,6 +80,64 @@ impl Task for LogoutTask {
     }
 }
 
+pub struct SearchTask {
+    criteria: SearchKey<'static>,
+    uid: bool,
+    /// We use this as scratch space.
+    numbers: Vec<NonZeroU32>,
+}
+
+impl SearchTask {
+    pub fn new(criteria: SearchKey<'static>, uid: bool) -> Self {
+        Self {
+            criteria,
+            uid,
+            numbers: Vec::new(),
+        }
+    }
+}
+
+impl Task for SearchTask {
+    type Output = Result<Vec<NonZeroU32>, &'static str>;
+
+    fn command_body(&self) -> CommandBody<'static> {
+        CommandBody::Search {
+            charset: None,
+            criteria: self.criteria.clone(),
+            uid: self.uid,
+        }
+    }
+
+    // Note: `command_body_rev2` isn't overridden. In IMAP4rev2, `SEARCH` without `RETURN` is the
+    // same as `RETURN (ALL)` and results in `ESEARCH`, see RFC 9051, section 6.4.4.
+
+    fn process_data(&mut self, data: Data<'static>) -> Option<Data<'static>> {
+        match data {
+            Data::Search(numbers) => {
+                self.numbers.extend(numbers);
+                None
+            }
+            unknown => Some(unknown),
+        }
+    }
+
+    fn process_esearch(&mut self, esearch: ESearchResponse) -> Option<ESearchResponse> {
+        for range in esearch.all.iter().flatten() {
+            self.numbers
+                .extend((range.start().get()..=range.end().get()).filter_map(NonZeroU32::new));
+        }
+        None
+    }
+
+    fn process_tagged(self, status_body: StatusBody<'static>) -> Self::Output {
+        match status_body.kind {
+            StatusKind::Ok => Ok(self.numbers),
+            StatusKind::No => Err("search failed, e.g., due to an unsupported charset"),
+            StatusKind::Bad => Err("command unknown or arguments invalid"),
+        }
+    }
+}
+
 pub struct AuthenticatePlainTask {
     line: Option<Vec<u8>>,
     ir: bool,
@@ -0,0 +1,480 @@
+//! Randomized tests for the cancel safety of `ClientFlow::progress` and `ServerFlow::progress`.
+//!
+//! Besides plain commands, `AUTHENTICATE` and the literals of `APPEND` are covered.
+//!
+//! The flows are driven over a mock stream that reads and writes random chunks and randomly
+//! returns `Poll::Pending`. The `progress` futures are dropped after a random number of polls.
+
+use std::{
+    future::Future,
+    pin::{pin, Pin},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+};
+
+use imap_codec::{
+    encode::Encoder,
+    imap_types::{
+        auth::{AuthMechanism, AuthenticateData},
+        command::{Command, CommandBody},
+        core::Tag,
+        mailbox::Mailbox,
+        response::{CommandContinuationRequest, Greeting, Response, Status},
+        secret::Secret,
+    },
+    CommandCodec,
+};
+use imap_flow::{
+    client::{ClientFlow, ClientFlowEvent, ClientFlowOptions},
+    server::{ServerFlow, ServerFlowEvent, ServerFlowOptions},
+    stream::AnyStream,
+    types::{AppendMessage, CommandAppend, LiteralReader},
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const ITERATIONS: u64 = 256;
+
+#[test]
+fn client_progress_is_cancel_safe() {
+    let command = Command::new(
+        Tag::unvalidated("A1"),
+        CommandBody::login("alice", "a\nbc").unwrap(),
+    )
+    .unwrap();
+    let expected_output = CommandCodec::default().encode(&command).dump();
+
+    for seed in 0..ITERATIONS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (stream, output) = MockStream::new(
+            b"* OK Hello\r\n+ Go\r\n* 1 FETCH (BODY[] {5}\r\nhello)\r\nA1 OK done\r\n",
+            rng.clone(),
+        );
+
+        let (mut client, _) = block_on(ClientFlow::receive_greeting(
+            AnyStream::new(stream),
+            ClientFlowOptions::default(),
+        ))
+        .unwrap();
+        client.enqueue_command(command.clone()).unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            if let Some(result) = poll_cancelling(client.progress(), &mut rng) {
+                events.push(result.unwrap());
+            }
+        }
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    ClientFlowEvent::CommandSent { .. },
+                    ClientFlowEvent::DataReceived { .. },
+                    ClientFlowEvent::StatusReceived {
+                        status: Status::Tagged(_)
+                    },
+                ]
+            ),
+            "seed {seed}: unexpected events: {events:?}"
+        );
+        assert_eq!(
+            *output.lock().unwrap(),
+            expected_output,
+            "seed {seed}: unexpected output"
+        );
+    }
+}
+
+#[test]
+fn client_authenticate_is_cancel_safe() {
+    let command = Command::new(
+        Tag::unvalidated("A1"),
+        CommandBody::authenticate(AuthMechanism::Plain),
+    )
+    .unwrap();
+
+    for seed in 0..ITERATIONS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (stream, output) =
+            MockStream::new(b"* OK Hello\r\n+ Go\r\nA1 OK done\r\n", rng.clone());
+
+        let (mut client, _) = block_on(ClientFlow::receive_greeting(
+            AnyStream::new(stream),
+            ClientFlowOptions::default(),
+        ))
+        .unwrap();
+        client.enqueue_command(command.clone()).unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            let Some(result) = poll_cancelling(client.progress(), &mut rng) else {
+                continue;
+            };
+
+            let event = result.unwrap();
+            if let ClientFlowEvent::ContinuationAuthenticateReceived { .. } = &event {
+                let authenticate_data =
+                    AuthenticateData::Continue(Secret::new(b"\0alice\0password".to_vec()));
+                client.authenticate_continue(authenticate_data).unwrap();
+            }
+            events.push(event);
+        }
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    ClientFlowEvent::AuthenticateStarted { .. },
+                    ClientFlowEvent::ContinuationAuthenticateReceived { .. },
+                    ClientFlowEvent::AuthenticateAccepted { .. },
+                ]
+            ),
+            "seed {seed}: unexpected events: {events:?}"
+        );
+        assert_eq!(
+            *output.lock().unwrap(),
+            b"A1 AUTHENTICATE PLAIN\r\nAGFsaWNlAHBhc3N3b3Jk\r\n",
+            "seed {seed}: unexpected output"
+        );
+    }
+}
+
+#[test]
+fn client_append_is_cancel_safe() {
+    for seed in 0..ITERATIONS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (stream, output) =
+            MockStream::new(b"* OK Hello\r\n+ Go\r\nA1 OK done\r\n", rng.clone());
+
+        let (mut client, _) = block_on(ClientFlow::receive_greeting(
+            AnyStream::new(stream),
+            ClientFlowOptions::default(),
+        ))
+        .unwrap();
+        let append = CommandAppend {
+            tag: Tag::unvalidated("A1"),
+            mailbox: Mailbox::Inbox,
+            messages: vec![AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(11, &b"hello world"[..]),
+            }],
+        };
+        client.enqueue_append(append).unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            if let Some(result) = poll_cancelling(client.progress(), &mut rng) {
+                events.push(result.unwrap());
+            }
+        }
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    ClientFlowEvent::AppendProgress {
+                        bytes_sent: 11,
+                        bytes_total: 11,
+                        ..
+                    },
+                    ClientFlowEvent::AppendSent { .. },
+                    ClientFlowEvent::StatusReceived {
+                        status: Status::Tagged(_)
+                    },
+                ]
+            ),
+            "seed {seed}: unexpected events: {events:?}"
+        );
+        assert_eq!(
+            *output.lock().unwrap(),
+            b"A1 APPEND INBOX {11}\r\nhello world\r\n",
+            "seed {seed}: unexpected output"
+        );
+    }
+}
+
+#[test]
+fn server_progress_is_cancel_safe() {
+    for seed in 0..ITERATIONS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (stream, output) =
+            MockStream::new(b"A1 NOOP\r\nA2 LOGIN alice {4}\r\na\nbc\r\n", rng.clone());
+
+        let (mut server, _) = block_on(ServerFlow::send_greeting(
+            AnyStream::new(stream),
+            ServerFlowOptions::default(),
+            Greeting::ok(None, "Hello").unwrap(),
+        ))
+        .unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            let Some(result) = poll_cancelling(server.progress(), &mut rng) else {
+                continue;
+            };
+
+            let event = result.unwrap();
+            if let ServerFlowEvent::CommandReceived { command } = &event {
+                if command.tag.as_ref() == "A1" {
+                    let status = Status::ok(Some(command.tag.clone()), None, "done").unwrap();
+                    server.enqueue_status(status).unwrap();
+                }
+            }
+            events.push(event);
+        }
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    ServerFlowEvent::CommandReceived { .. },
+                    ServerFlowEvent::ResponseSent {
+                        response: Response::Status(_),
+                        ..
+                    },
+                    ServerFlowEvent::CommandReceived { .. },
+                ]
+            ),
+            "seed {seed}: unexpected events: {events:?}"
+        );
+        assert_eq!(
+            *output.lock().unwrap(),
+            b"* OK Hello\r\nA1 OK done\r\n+ ...\r\n",
+            "seed {seed}: unexpected output"
+        );
+    }
+}
+
+#[test]
+fn server_authenticate_is_cancel_safe() {
+    for seed in 0..ITERATIONS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (stream, output) = MockStream::new(
+            b"A1 AUTHENTICATE PLAIN\r\nAGFsaWNlAHBhc3N3b3Jk\r\n",
+            rng.clone(),
+        );
+
+        let (mut server, _) = block_on(ServerFlow::send_greeting(
+            AnyStream::new(stream),
+            ServerFlowOptions::default(),
+            Greeting::ok(None, "Hello").unwrap(),
+        ))
+        .unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 4 {
+            let Some(result) = poll_cancelling(server.progress(), &mut rng) else {
+                continue;
+            };
+
+            let event = result.unwrap();
+            match &event {
+                ServerFlowEvent::CommandAuthenticateReceived {
+                    command_authenticate,
+                } => {
+                    assert_eq!(command_authenticate.tag.as_ref(), "A1");
+                    let continuation = CommandContinuationRequest::basic(None, "Go").unwrap();
+                    server.authenticate_continue(continuation).unwrap();
+                }
+                ServerFlowEvent::AuthenticateDataReceived { .. } => {
+                    let status = Status::ok(Some(Tag::unvalidated("A1")), None, "done").unwrap();
+                    server.authenticate_finish(status).unwrap();
+                }
+                _ => {}
+            }
+            events.push(event);
+        }
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    ServerFlowEvent::CommandAuthenticateReceived { .. },
+                    ServerFlowEvent::ResponseSent {
+                        response: Response::CommandContinuationRequest(_),
+                        ..
+                    },
+                    ServerFlowEvent::AuthenticateDataReceived { .. },
+                    ServerFlowEvent::ResponseSent {
+                        response: Response::Status(_),
+                        ..
+                    },
+                ]
+            ),
+            "seed {seed}: unexpected events: {events:?}"
+        );
+        assert_eq!(
+            *output.lock().unwrap(),
+            b"* OK Hello\r\n+ Go\r\nA1 OK done\r\n",
+            "seed {seed}: unexpected output"
+        );
+    }
+}
+
+#[test]
+fn server_append_literal_is_cancel_safe() {
+    let options = ServerFlowOptions {
+        announce_literals: true,
+        ..Default::default()
+    };
+
+    for seed in 0..ITERATIONS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (stream, output) =
+            MockStream::new(b"A1 APPEND INBOX {11}\r\nhello world\r\n", rng.clone());
+
+        let (mut server, _) = block_on(ServerFlow::send_greeting(
+            AnyStream::new(stream),
+            options.clone(),
+            Greeting::ok(None, "Hello").unwrap(),
+        ))
+        .unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            let Some(result) = poll_cancelling(server.progress(), &mut rng) else {
+                continue;
+            };
+
+            let event = result.unwrap();
+            if let ServerFlowEvent::LiteralAnnounced { .. } = &event {
+                server.literal_accept().unwrap();
+            }
+            events.push(event);
+        }
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    ServerFlowEvent::LiteralAnnounced { length: 11, .. },
+                    ServerFlowEvent::CommandReceived {
+                        command: Command {
+                            body: CommandBody::Append { .. },
+                            ..
+                        }
+                    },
+                ]
+            ),
+            "seed {seed}: unexpected events: {events:?}"
+        );
+        assert_eq!(
+            *output.lock().unwrap(),
+            b"* OK Hello\r\n+ ...\r\n",
+            "seed {seed}: unexpected output"
+        );
+    }
+}
+
+/// Polls the future a random number of times and drops it if it didn't complete.
+fn poll_cancelling<F: Future>(future: F, rng: &mut StdRng) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    for _ in 0..rng.gen_range(1..=4) {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+    }
+
+    None
+}
+
+/// Polls the future until it completes.
+///
+/// This works because the mock stream never blocks indefinitely.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Stream reading the given input and recording the output in random chunks.
+#[derive(Debug)]
+struct MockStream {
+    input: &'static [u8],
+    output: Arc<Mutex<Vec<u8>>>,
+    rng: StdRng,
+}
+
+impl MockStream {
+    fn new(input: &'static [u8], rng: StdRng) -> (Self, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::default();
+        let stream = Self {
+            input,
+            output: Arc::clone(&output),
+            rng,
+        };
+
+        (stream, output)
+    }
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.rng.gen_bool(0.3) {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let max = self.input.len().min(buf.remaining()).min(8);
+        if max > 0 {
+            let count = self.rng.gen_range(1..=max);
+            let (chunk, input) = self.input.split_at(count);
+            buf.put_slice(chunk);
+            self.input = input;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if self.rng.gen_bool(0.3) {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let count = self.rng.gen_range(1..=buf.len().min(8));
+        self.output.lock().unwrap().extend_from_slice(&buf[..count]);
+
+        Poll::Ready(Ok(count))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
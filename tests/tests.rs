@@ -1,15 +1,32 @@
-use imap_codec::imap_types::{
-    auth::AuthMechanism,
-    command::{Command, CommandBody},
-    core::Tag,
-    response::{Greeting, Status},
+use std::num::NonZeroU32;
+
+use bounded_static::IntoBoundedStatic;
+use bytes::Bytes;
+use imap_codec::{
+    decode::Decoder,
+    imap_types::{
+        auth::AuthMechanism,
+        command::{Command, CommandBody},
+        core::{LiteralMode, Tag, Text},
+        flag::Flag,
+        mailbox::Mailbox,
+        response::{Bye, Data, Greeting, Response, Status, StatusBody, StatusKind, Tagged},
+    },
+    CommandCodec, ResponseCodec,
 };
 use imap_flow::{
-    client::{ClientFlow, ClientFlowEvent, ClientFlowOptions},
-    server::{ServerFlow, ServerFlowEvent, ServerFlowOptions},
-    stream::AnyStream,
+    client::{
+        ClientFlow, ClientFlowEnqueueAppendError, ClientFlowError, ClientFlowEvent,
+        ClientFlowGreeting, ClientFlowOptions, ClientFlowState,
+    },
+    server::{ServerFlow, ServerFlowError, ServerFlowEvent, ServerFlowOptions},
+    stream::{AnyStream, StreamError},
+    types::{AppendMessage, CommandAppend, EnabledExtensions, LiteralReader, ProtocolVersion},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
 };
-use tokio::net::{TcpListener, TcpStream};
 
 #[tokio::test]
 async fn self_test() {
@@ -37,13 +54,13 @@ async fn self_test() {
                 match server.progress().await.unwrap() {
                     ServerFlowEvent::CommandReceived { command } => {
                         let no = Status::no(Some(command.tag), None, "...").unwrap();
-                        server.enqueue_status(no);
+                        server.enqueue_status(no).unwrap();
                     }
                     ServerFlowEvent::CommandAuthenticateReceived {
                         command_authenticate,
                     } => {
                         let no = Status::no(Some(command_authenticate.tag), None, "...").unwrap();
-                        server.enqueue_status(no);
+                        server.enqueue_status(no).unwrap();
                     }
                     _ => {}
                 }
@@ -60,26 +77,2061 @@ async fn self_test() {
             .unwrap()
     };
 
-    assert_eq!(greeting, received_greeting);
+    assert_eq!(greeting, Greeting::from(received_greeting));
 
-    client.enqueue_command(Command::new(Tag::unvalidated("A1"), CommandBody::Capability).unwrap());
+    client
+        .enqueue_command(Command::new(Tag::unvalidated("A1"), CommandBody::Capability).unwrap())
+        .unwrap();
 
     loop {
         match client.progress().await.unwrap() {
             ClientFlowEvent::StatusReceived { .. } => {
-                client.enqueue_command(
-                    Command::new(
-                        Tag::unvalidated("A2"),
-                        CommandBody::Authenticate {
-                            mechanism: AuthMechanism::Plain,
-                            initial_response: None,
-                        },
+                client
+                    .enqueue_command(
+                        Command::new(
+                            Tag::unvalidated("A2"),
+                            CommandBody::Authenticate {
+                                mechanism: AuthMechanism::Plain,
+                                initial_response: None,
+                            },
+                        )
+                        .unwrap(),
                     )
-                    .unwrap(),
-                );
+                    .unwrap();
             }
             ClientFlowEvent::AuthenticateRejected { .. } => break,
             _ => {}
         }
     }
 }
+
+#[tokio::test]
+async fn client_skips_malformed_response_with_literal() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n* XYZ {5}\r\nhello\r\n* OK done\r\n")
+        .await
+        .unwrap();
+
+    let options = ClientFlowOptions {
+        skip_malformed_messages: true,
+        ..Default::default()
+    };
+    let (mut client, _) = ClientFlow::receive_greeting(AnyStream::new(client_stream), options)
+        .await
+        .unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::MalformedMessageSkipped { discarded_bytes } => {
+            assert_eq!(&*discarded_bytes, b"* XYZ {5}\r\nhello\r\n");
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::StatusReceived { .. } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn server_skips_malformed_command_with_literal() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    client_stream
+        .write_all(b"A1 FOO {3+}\r\nabc\r\nA2 NOOP\r\n")
+        .await
+        .unwrap();
+
+    let options = ServerFlowOptions {
+        skip_malformed_messages: true,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::MalformedMessageSkipped { discarded_bytes } => {
+            assert_eq!(&*discarded_bytes, b"A1 FOO {3+}\r\nabc\r\n");
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => {
+            assert_eq!(command.tag, Tag::unvalidated("A2"));
+            assert_eq!(command.body, CommandBody::Noop);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn server_skips_malformed_command_without_waiting_for_sync_literal() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    // The client won't send the synchronizing literal without a continuation request.
+    client_stream
+        .write_all(b"A1 FOO {3+}\r\nabc {3}\r\nA2 NOOP\r\n")
+        .await
+        .unwrap();
+
+    let options = ServerFlowOptions {
+        skip_malformed_messages: true,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::MalformedMessageSkipped { discarded_bytes } => {
+            assert_eq!(&*discarded_bytes, b"A1 FOO {3+}\r\nabc {3}\r\n");
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => {
+            assert_eq!(command.tag, Tag::unvalidated("A2"));
+            assert_eq!(command.body, CommandBody::Noop);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn client_changes_options_at_message_boundary() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello\r\n* 1 FETCH (BODY[] {10}\r\nhello")
+        .await
+        .unwrap();
+
+    let options = ClientFlowOptions {
+        crlf_relaxed: false,
+        literal_progress_granularity: Some(5),
+        ..Default::default()
+    };
+    let (mut client, _) = ClientFlow::receive_greeting(AnyStream::new(client_stream), options)
+        .await
+        .unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::LiteralProgress { announced, .. } => assert_eq!(announced, 10),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // The response in progress is still received with the old options.
+    client.set_options(ClientFlowOptions::default());
+    assert!(!client.options().crlf_relaxed);
+
+    server_stream
+        .write_all(b"world)\r\n* 2 EXISTS\n")
+        .await
+        .unwrap();
+
+    loop {
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::LiteralProgress { .. } => {}
+            ClientFlowEvent::DataReceived {
+                data: Data::Fetch { .. },
+            } => break,
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    // The next response is received with the new options.
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::DataReceived {
+            data: Data::Exists(2),
+        } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert!(client.options().crlf_relaxed);
+}
+
+#[tokio::test]
+async fn client_collects_statistics() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    let input = b"* OK Hello, World!\r\n* 1 FETCH (BODY[] {5}\r\nhello)\r\nA1 OK done\r\n";
+    server_stream.write_all(input).await.unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    client
+        .enqueue_command(Command::new(Tag::unvalidated("A1"), CommandBody::Noop).unwrap())
+        .unwrap();
+    assert_eq!(client.statistics().send_queue_len, 1);
+
+    for _ in 0..3 {
+        client.progress().await.unwrap();
+    }
+
+    let statistics = client.statistics();
+    assert_eq!(statistics.bytes_read, input.len() as u64);
+    assert_eq!(statistics.bytes_written, b"A1 NOOP\r\n".len() as u64);
+    assert_eq!(statistics.commands_sent, 1);
+    assert_eq!(statistics.data_received, 1);
+    assert_eq!(statistics.status_received, 1);
+    assert_eq!(statistics.literals_received, 1);
+    assert_eq!(statistics.send_queue_len, 0);
+    assert_eq!(statistics.read_buffer_len, 0);
+}
+
+#[tokio::test]
+async fn client_reserves_read_buffer_in_steps_for_literal() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    let line = b"* 1 FETCH (BODY[] {1000000}\r\n";
+    server_stream.write_all(b"* OK Hello\r\n").await.unwrap();
+    server_stream.write_all(line).await.unwrap();
+    server_stream.write_all(&[b'a'; 100]).await.unwrap();
+
+    let options = ClientFlowOptions {
+        literal_progress_granularity: Some(1),
+        ..Default::default()
+    };
+    let (mut client, _) = ClientFlow::receive_greeting(AnyStream::new(client_stream), options)
+        .await
+        .unwrap();
+
+    loop {
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::LiteralProgress { received: 100, .. } => break,
+            ClientFlowEvent::LiteralProgress { .. } => {}
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    // Only the received bytes are buffered and the announced length is not reserved at once.
+    let statistics = client.statistics();
+    assert_eq!(statistics.read_buffer_len, line.len() + 100);
+    assert!(statistics.read_buffer_capacity <= 2 * 64 * 1024);
+}
+
+#[tokio::test]
+async fn client_shrinks_read_buffer_after_large_literal() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    let length = 256 * 1024;
+    let server = tokio::task::spawn(async move {
+        server_stream.write_all(b"* OK Hello\r\n").await.unwrap();
+        server_stream
+            .write_all(format!("* 1 FETCH (BODY[] {{{length}}}\r\n").as_bytes())
+            .await
+            .unwrap();
+        server_stream.write_all(&vec![b'a'; length]).await.unwrap();
+        server_stream.write_all(b")\r\n").await.unwrap();
+        server_stream
+    });
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::DataReceived { .. } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // The allocation for the literal was released.
+    let statistics = client.statistics();
+    assert_eq!(statistics.read_buffer_len, 0);
+    assert_eq!(statistics.read_buffer_capacity, 0);
+
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn client_borrows_data() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n* 1 FETCH (BODY[] {5}\r\nhello)\r\n* OK done\r\n")
+        .await
+        .unwrap();
+
+    let options = ClientFlowOptions {
+        borrow_data: true,
+        ..Default::default()
+    };
+    let (mut client, _) = ClientFlow::receive_greeting(AnyStream::new(client_stream), options)
+        .await
+        .unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::DataAvailable => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert!(matches!(client.data(), Some(Data::Fetch { .. })));
+    // The response is decoded once, every call returns the same `Data`.
+    assert!(std::ptr::eq(client.data().unwrap(), client.data().unwrap()));
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::StatusReceived { .. } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert!(client.data().is_none());
+}
+
+#[tokio::test]
+async fn client_reports_literal_progress() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n")
+        .await
+        .unwrap();
+
+    let options = ClientFlowOptions {
+        literal_progress_granularity: Some(4),
+        ..Default::default()
+    };
+    let (mut client, _) = ClientFlow::receive_greeting(AnyStream::new(client_stream), options)
+        .await
+        .unwrap();
+
+    server_stream
+        .write_all(b"* 1 FETCH (BODY[] {10}\r\n0123")
+        .await
+        .unwrap();
+
+    let (event, _) = tokio::join!(client.progress(), server_stream.write_all(b"4567"));
+    match event.unwrap() {
+        ClientFlowEvent::LiteralProgress {
+            announced,
+            received,
+        } => {
+            assert_eq!(announced, 10);
+            assert_eq!(received, 8);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // Less than 4 bytes were received since the last event, so the response follows directly.
+    let (event, _) = tokio::join!(client.progress(), server_stream.write_all(b"89)\r\n"));
+    match event.unwrap() {
+        ClientFlowEvent::DataReceived { .. } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn client_appends_from_readers() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n+ go\r\n+ go\r\nA1 OK done\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let append = CommandAppend {
+        tag: Tag::try_from("A1").unwrap(),
+        mailbox: Mailbox::Inbox,
+        messages: vec![
+            AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(5, &b"hello"[..]),
+            },
+            AppendMessage {
+                flags: vec![Flag::Seen],
+                date: None,
+                binary: true,
+                literal: LiteralReader::new(3, &b"f\0o"[..]),
+            },
+        ],
+    };
+    let enqueued_handle = client.enqueue_append(append).unwrap();
+
+    for expected_bytes_sent in [5, 8] {
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::AppendProgress {
+                handle,
+                bytes_sent,
+                bytes_total,
+            } => {
+                assert_eq!(handle, enqueued_handle);
+                assert_eq!(bytes_sent, expected_bytes_sent);
+                assert_eq!(bytes_total, 8);
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::AppendSent { handle, .. } => assert_eq!(handle, enqueued_handle),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::StatusReceived { .. } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    drop(client);
+    let mut output = Vec::new();
+    server_stream.read_to_end(&mut output).await.unwrap();
+    assert_eq!(
+        output,
+        b"A1 APPEND INBOX {5}\r\nhello (\\Seen) ~{3}\r\nf\0o\r\n"
+    );
+}
+
+#[tokio::test]
+async fn client_refuses_append_without_messages() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let append = CommandAppend {
+        tag: Tag::try_from("A1").unwrap(),
+        mailbox: Mailbox::Inbox,
+        messages: Vec::new(),
+    };
+    match client.enqueue_append(append) {
+        Err(ClientFlowEnqueueAppendError::Encoding { append }) => {
+            assert_eq!(append.tag.as_ref(), "A1");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+}
+
+#[tokio::test]
+async fn client_sends_non_sync_literals_without_continuation() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let input = b"A1 LOGIN {5+}\r\nalice {6+}\r\nsecret\r\n";
+    let (_, command) = CommandCodec::default().decode(input).unwrap();
+    client.enqueue_command(command.into_static()).unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::CommandSent { .. } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    drop(client);
+    let mut output = Vec::new();
+    server_stream.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output, input);
+}
+
+#[tokio::test]
+async fn client_reports_rejected_multiappend_message() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n+ go\r\nA1 BAD Too big\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let append = CommandAppend {
+        tag: Tag::try_from("A1").unwrap(),
+        mailbox: Mailbox::Inbox,
+        messages: vec![
+            AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(5, &b"hello"[..]),
+            },
+            AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(3, &b"foo"[..]),
+            },
+        ],
+    };
+    let enqueued_handle = client.enqueue_append(append).unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::AppendProgress { bytes_sent, .. } => assert_eq!(bytes_sent, 5),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // The server refused the second message.
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::AppendRejected {
+            handle,
+            literal_index,
+            ..
+        } => {
+            assert_eq!(handle, enqueued_handle);
+            assert_eq!(literal_index, 1);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    drop(client);
+    let mut output = Vec::new();
+    server_stream.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output, b"A1 APPEND INBOX {5}\r\nhello {3}\r\n");
+}
+
+#[tokio::test]
+async fn flows_abort_command_at_second_literal() {
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let server = async move {
+        let options = ServerFlowOptions {
+            announce_literals: true,
+            ..Default::default()
+        };
+        let (mut server, _) = ServerFlow::send_greeting(
+            AnyStream::new(server_stream),
+            options,
+            Greeting::ok(None, "Hello, World!").unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // Runs until the client closes the stream.
+        while let Ok(event) = server.progress().await {
+            match event {
+                ServerFlowEvent::LiteralAnnounced { index: 0, .. } => {
+                    server.literal_accept().unwrap();
+                }
+                ServerFlowEvent::LiteralAnnounced { .. } => {
+                    server
+                        .literal_reject(None, Text::try_from("Password too long").unwrap())
+                        .unwrap();
+                }
+                ServerFlowEvent::CommandReceived { command } => {
+                    assert_eq!(command.body, CommandBody::Noop);
+                    server
+                        .enqueue_status(Status::ok(Some(command.tag), None, "done").unwrap())
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+    };
+    tokio::task::spawn(server);
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    // Both arguments contain a newline, so they are sent as literals.
+    let command = Command::new(
+        Tag::unvalidated("A1"),
+        CommandBody::login("al\nice", "se\ncret").unwrap(),
+    )
+    .unwrap();
+    let handle = client.enqueue_command(command).unwrap();
+
+    // The `NO` ends the command while waiting for the continuation of the second literal.
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::CommandRejected {
+            handle: rejected_handle,
+            literal_index,
+            status,
+            ..
+        } => {
+            assert_eq!(rejected_handle, handle);
+            assert_eq!(literal_index, 1);
+            assert!(matches!(
+                status,
+                Status::Tagged(Tagged {
+                    body: StatusBody {
+                        kind: StatusKind::No,
+                        ..
+                    },
+                    ..
+                })
+            ));
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // The flow continues with the next command.
+    client
+        .enqueue_command(Command::new(Tag::unvalidated("A2"), CommandBody::Noop).unwrap())
+        .unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::CommandSent { command, .. } => assert_eq!(command.tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::StatusReceived {
+            status: Status::Tagged(Tagged { tag, .. }),
+        } => assert_eq!(tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn flows_multiappend() {
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let server = async move {
+        let (mut server, _) = ServerFlow::send_greeting(
+            AnyStream::new(server_stream),
+            ServerFlowOptions::default(),
+            Greeting::ok(None, "Hello, World!").unwrap(),
+        )
+        .await
+        .unwrap();
+
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::MultiAppendReceived { commands } => {
+                let expected = [
+                    CommandBody::append(Mailbox::Inbox, Vec::new(), None, b"hello".as_ref()),
+                    CommandBody::append(Mailbox::Inbox, vec![Flag::Seen], None, b"foo".as_ref()),
+                ]
+                .map(|body| Command::new(Tag::unvalidated("A1"), body.unwrap()).unwrap());
+                assert_eq!(commands, expected);
+
+                server
+                    .enqueue_status(Status::ok(Some(Tag::unvalidated("A1")), None, "done").unwrap())
+                    .unwrap();
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+
+        while server.progress().await.is_ok() {}
+    };
+    tokio::task::spawn(server);
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let append = CommandAppend {
+        tag: Tag::try_from("A1").unwrap(),
+        mailbox: Mailbox::Inbox,
+        messages: vec![
+            AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(5, &b"hello"[..]),
+            },
+            AppendMessage {
+                flags: vec![Flag::Seen],
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(3, &b"foo"[..]),
+            },
+        ],
+    };
+    let enqueued_handle = client.enqueue_append(append).unwrap();
+
+    for expected_bytes_sent in [5, 8] {
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::AppendProgress { bytes_sent, .. } => {
+                assert_eq!(bytes_sent, expected_bytes_sent)
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::AppendSent { handle, .. } => assert_eq!(handle, enqueued_handle),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::StatusReceived {
+            status:
+                Status::Tagged(Tagged {
+                    tag,
+                    body:
+                        StatusBody {
+                            kind: StatusKind::Ok,
+                            ..
+                        },
+                }),
+        } => assert_eq!(tag.as_ref(), "A1"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn flows_reject_second_message_of_multiappend() {
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let server = async move {
+        let options = ServerFlowOptions {
+            announce_literals: true,
+            ..Default::default()
+        };
+        let (mut server, _) = ServerFlow::send_greeting(
+            AnyStream::new(server_stream),
+            options,
+            Greeting::ok(None, "Hello, World!").unwrap(),
+        )
+        .await
+        .unwrap();
+
+        loop {
+            match server.progress().await {
+                Ok(ServerFlowEvent::LiteralAnnounced { index: 0, .. }) => {
+                    server.literal_accept().unwrap();
+                }
+                Ok(ServerFlowEvent::LiteralAnnounced { index, .. }) => {
+                    assert_eq!(index, 1);
+                    server
+                        .literal_reject(None, Text::try_from("Message too long").unwrap())
+                        .unwrap();
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    };
+    tokio::task::spawn(server);
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let append = CommandAppend {
+        tag: Tag::try_from("A1").unwrap(),
+        mailbox: Mailbox::Inbox,
+        messages: vec![
+            AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(5, &b"hello"[..]),
+            },
+            AppendMessage {
+                flags: Vec::new(),
+                date: None,
+                binary: false,
+                literal: LiteralReader::new(3, &b"foo"[..]),
+            },
+        ],
+    };
+    let enqueued_handle = client.enqueue_append(append).unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::AppendProgress { bytes_sent, .. } => assert_eq!(bytes_sent, 5),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::AppendRejected {
+            handle,
+            literal_index,
+            ..
+        } => {
+            assert_eq!(handle, enqueued_handle);
+            assert_eq!(literal_index, 1);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn server_rejects_malformed_command() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let options = ServerFlowOptions {
+        reject_malformed_commands: true,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream.write_all(b"A1 FOO BAR\r\n").await.unwrap();
+
+    match server.progress().await {
+        Err(ServerFlowError::MalformedMessage { discarded_bytes }) => {
+            assert_eq!(&*discarded_bytes, b"A1 FOO BAR\r\n");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+
+    // Send the enqueued `BAD` in the background.
+    tokio::task::spawn(async move { while server.progress().await.is_ok() {} });
+
+    let mut line = String::new();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* OK Hello, World!\r\n");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("A1 BAD "), "unexpected line: {line:?}");
+}
+
+#[tokio::test]
+async fn server_rejects_command_with_lf() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let options = ServerFlowOptions {
+        crlf_relaxed: false,
+        reject_malformed_commands: true,
+        max_consecutive_malformed_commands: Some(1),
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 NOOP\nA2 NOOP\n")
+        .await
+        .unwrap();
+
+    match server.progress().await {
+        Err(ServerFlowError::ExpectedCrlfGotLf { discarded_bytes }) => {
+            assert_eq!(&*discarded_bytes, b"A1 NOOP\n");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+
+    // The second command exceeds the limit of consecutive malformed commands.
+    match server.progress().await {
+        Err(ServerFlowError::TooManyMalformedCommands { discarded_bytes }) => {
+            assert_eq!(&*discarded_bytes, b"A2 NOOP\n");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+
+    // Send the enqueued `BAD` and `BYE` in the background.
+    tokio::task::spawn(async move { while server.progress().await.is_ok() {} });
+
+    let mut line = String::new();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* OK Hello, World!\r\n");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("A1 BAD "), "unexpected line: {line:?}");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("* BYE "), "unexpected line: {line:?}");
+}
+
+#[tokio::test]
+async fn server_rejects_announced_literal() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let options = ServerFlowOptions {
+        announce_literals: true,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 APPEND INBOX {5+}\r\nhello\r\nA2 NOOP\r\n")
+        .await
+        .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::LiteralAnnounced {
+            tag,
+            length,
+            mode,
+            binary,
+            index,
+        } => {
+            assert_eq!(tag.as_ref(), "A1");
+            assert_eq!(length, 5);
+            assert_eq!(mode, LiteralMode::NonSync);
+            assert!(!binary);
+            assert_eq!(index, 0);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    server
+        .literal_reject(None, Text::try_from("Over quota").unwrap())
+        .unwrap();
+
+    // The rejected command is skipped including its literal.
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    let mut line = String::new();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* OK Hello, World!\r\n");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "A1 NO Over quota\r\n");
+}
+
+#[tokio::test]
+async fn server_accepts_literal8_only_with_binary() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 APPEND INBOX ~{3+}\r\na\0b\r\nA2 NOOP\r\n")
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        server.progress().await,
+        Err(ServerFlowError::Literal8Rejected { .. })
+    ));
+
+    // The rejected command is skipped including its literal.
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    let mut line = String::new();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* OK Hello, World!\r\n");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "A1 BAD [UNKNOWN-CTE] ...\r\n");
+
+    server.set_options(ServerFlowOptions {
+        binary: true,
+        ..Default::default()
+    });
+
+    client_stream
+        .write_all(b"A3 APPEND INBOX ~{3+}\r\na\0b\r\n")
+        .await
+        .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A3"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn server_reports_literal_progress() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    let options = ServerFlowOptions {
+        literal_progress_granularity: Some(5),
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 APPEND INBOX {10+}\r\n")
+        .await
+        .unwrap();
+
+    // The progress is reported after the server read the bytes of the literal.
+    let (event, ()) = tokio::join!(server.progress(), async {
+        client_stream.write_all(b"hello").await.unwrap();
+    });
+    match event.unwrap() {
+        ServerFlowEvent::LiteralProgress {
+            announced,
+            received,
+        } => {
+            assert_eq!(announced, 10);
+            assert_eq!(received, 5);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    client_stream.write_all(b"world\r\n").await.unwrap();
+
+    loop {
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::LiteralProgress { announced, .. } => assert_eq!(announced, 10),
+            ServerFlowEvent::CommandReceived { command } => {
+                assert_eq!(command.tag.as_ref(), "A1");
+                break;
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn server_tracks_tags_in_flight() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 NOOP\r\nA1 NOOP\r\n")
+        .await
+        .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A1"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert_eq!(server.tags_in_flight(), [Tag::unvalidated("A1")]);
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::DuplicateTagReceived { command } => {
+            assert_eq!(command.tag.as_ref(), "A1")
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert_eq!(server.tags_in_flight().len(), 2);
+    assert_eq!(server.statistics().duplicate_tags_received, 1);
+
+    // Every command using the tag must be completed.
+    let bad = Status::bad(Some(Tag::unvalidated("A1")), None, "Duplicate tag").unwrap();
+    server.enqueue_status(bad).unwrap();
+    assert_eq!(server.tags_in_flight().len(), 1);
+
+    let ok = Status::ok(Some(Tag::unvalidated("A1")), None, "done").unwrap();
+    server.enqueue_status(ok.clone()).unwrap();
+    assert!(server.tags_in_flight().is_empty());
+
+    // A tagged status for a tag that is not in flight is counted.
+    server.enqueue_status(ok).unwrap();
+    assert_eq!(server.statistics().unexpected_tagged_statuses, 1);
+
+    client_stream.write_all(b"A2 NOOP\r\n").await.unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // Tagged statuses in raw responses complete tags, too. The literal is not inspected.
+    server
+        .enqueue_raw(Bytes::from_static(
+            b"* 1 FETCH (BODY[] {12}\r\nA3 OK done\r\n)\r\nA2 OK done\r\n",
+        ))
+        .unwrap();
+    assert!(server.tags_in_flight().is_empty());
+    assert_eq!(server.statistics().unexpected_tagged_statuses, 1);
+}
+
+#[tokio::test]
+async fn server_reports_duplicate_authenticate_tag() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 NOOP\r\nA1 AUTHENTICATE PLAIN\r\nA2 NOOP\r\n")
+        .await
+        .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A1"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::DuplicateTagReceived { command } => {
+            assert_eq!(command.tag.as_ref(), "A1");
+            assert!(matches!(command.body, CommandBody::Authenticate { .. }));
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert_eq!(server.statistics().duplicate_tags_received, 1);
+
+    // The authentication was not started, so the next line is a command.
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert_eq!(server.tags_in_flight().len(), 3);
+}
+
+#[tokio::test]
+async fn server_defers_options_change_during_literal() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let options = ServerFlowOptions {
+        announce_literals: true,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options.clone(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    client_stream
+        .write_all(b"A1 APPEND INBOX {5}\r\n")
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        server.progress().await.unwrap(),
+        ServerFlowEvent::LiteralAnnounced { .. }
+    ));
+
+    // Changing the options in the middle of a command is deferred.
+    server.set_options(ServerFlowOptions {
+        max_literal_size: 1,
+        announce_literals: false,
+        ..options
+    });
+    assert!(server.options().announce_literals);
+    server.literal_accept().unwrap();
+
+    client_stream
+        .write_all(b"hello\r\nA2 APPEND INBOX {5}\r\n")
+        .await
+        .unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A1"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // The next command is received with the new options.
+    assert!(matches!(
+        server.progress().await,
+        Err(ServerFlowError::LiteralTooLong { .. })
+    ));
+    assert_eq!(server.options().max_literal_size, 1);
+}
+
+#[tokio::test]
+async fn client_handles_preauth_and_bye_greeting() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream
+        .write_all(b"* PREAUTH Hello\r\n")
+        .await
+        .unwrap();
+
+    let (client, greeting) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    assert!(matches!(greeting, ClientFlowGreeting::PreAuth { .. }));
+    assert_eq!(client.state(), ClientFlowState::Authenticated);
+
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream
+        .write_all(b"* BYE Overloaded\r\n")
+        .await
+        .unwrap();
+
+    let result =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await;
+    match result {
+        Err(ClientFlowError::GreetingBye { text, .. }) => assert_eq!(text.as_ref(), "Overloaded"),
+        result => panic!("unexpected result: {result:?}"),
+    }
+}
+
+#[tokio::test]
+async fn client_observes_enabled_extensions() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream
+        .write_all(b"* OK Hello\r\n* ENABLED UTF8=ACCEPT\r\n* ENABLED IMAP4rev2\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    assert!(!client.enabled().utf8_accept);
+
+    assert!(matches!(
+        client.progress().await.unwrap(),
+        ClientFlowEvent::DataReceived { .. }
+    ));
+    assert!(client.enabled().utf8_accept);
+    assert_eq!(client.protocol_version(), ProtocolVersion::Imap4rev1);
+
+    assert!(matches!(
+        client.progress().await.unwrap(),
+        ClientFlowEvent::DataReceived { .. }
+    ));
+    assert_eq!(client.protocol_version(), ProtocolVersion::Imap4rev2);
+}
+
+#[tokio::test]
+async fn client_accepts_imap4rev2_responses() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream
+        .write_all(b"* OK Hello\r\n* ESEARCH (TAG \"A1\") UID ALL 1:3,5 COUNT 4\r\n* 2 RECENT\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    client.set_enabled(EnabledExtensions {
+        utf8_accept: true,
+        imap4rev2: true,
+    });
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::ESearchReceived { esearch } => {
+            assert_eq!(esearch.tag, Some(Tag::unvalidated("A1")));
+            assert!(esearch.uid);
+            let one = NonZeroU32::new(1).unwrap();
+            let three = NonZeroU32::new(3).unwrap();
+            let five = NonZeroU32::new(5).unwrap();
+            assert_eq!(esearch.all, Some(vec![one..=three, five..=five]));
+            assert_eq!(esearch.count, Some(4));
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // `RECENT` was removed in IMAP4rev2 but is still returned.
+    assert!(matches!(
+        client.progress().await.unwrap(),
+        ClientFlowEvent::DataReceived {
+            data: Data::Recent(2)
+        }
+    ));
+    assert_eq!(client.statistics().unexpected_responses, 1);
+}
+
+#[tokio::test]
+async fn client_rejects_esearch_in_imap4rev1() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream
+        .write_all(b"* OK Hello\r\n* ESEARCH COUNT 4\r\n* 2 RECENT\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    match client.progress().await {
+        Err(ClientFlowError::MalformedMessage { discarded_bytes }) => {
+            assert_eq!(&*discarded_bytes, b"* ESEARCH COUNT 4\r\n");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+
+    assert!(matches!(
+        client.progress().await.unwrap(),
+        ClientFlowEvent::DataReceived {
+            data: Data::Recent(2)
+        }
+    ));
+    assert_eq!(client.statistics().unexpected_responses, 0);
+}
+
+#[test]
+fn enabled_extensions_convert_mailbox_names() {
+    let rev1 = EnabledExtensions::default();
+    let mailbox = rev1.mailbox("Entwürfe").unwrap();
+    assert_eq!(mailbox, Mailbox::try_from("Entw&APw-rfe").unwrap());
+    assert_eq!(rev1.mailbox_name(&mailbox).as_deref(), Some("Entwürfe"));
+    assert_eq!(
+        rev1.mailbox_name(&Mailbox::try_from("Entwürfe").unwrap()),
+        None
+    );
+
+    let utf8 = EnabledExtensions {
+        utf8_accept: true,
+        ..Default::default()
+    };
+    let mailbox = utf8.mailbox("Entwürfe").unwrap();
+    assert_eq!(mailbox, Mailbox::try_from("Entwürfe").unwrap());
+    assert_eq!(utf8.mailbox_name(&mailbox).as_deref(), Some("Entwürfe"));
+}
+
+#[tokio::test]
+async fn flows_round_trip_utf8_mailbox_name() {
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let server = async move {
+        let (mut server, _) = ServerFlow::send_greeting(
+            AnyStream::new(server_stream),
+            ServerFlowOptions::default(),
+            Greeting::ok(None, "Hello, World!").unwrap(),
+        )
+        .await
+        .unwrap();
+
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::CommandReceived { command } => {
+                assert!(matches!(command.body, CommandBody::Enable { .. }));
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+        let (_, enabled) = ResponseCodec::default()
+            .decode(b"* ENABLED UTF8=ACCEPT\r\n")
+            .unwrap();
+        let Response::Data(enabled) = enabled else {
+            panic!("unexpected response: {enabled:?}");
+        };
+        server.enqueue_data(enabled).unwrap();
+        server
+            .enqueue_status(Status::ok(Some(Tag::unvalidated("A1")), None, "done").unwrap())
+            .unwrap();
+
+        loop {
+            if let ServerFlowEvent::CommandReceived { command } = server.progress().await.unwrap() {
+                // The flow observed the sent `ENABLED`.
+                assert!(server.enabled().utf8_accept);
+                let CommandBody::Create { mailbox } = command.body else {
+                    panic!("unexpected command: {command:?}");
+                };
+                return server.enabled().mailbox_name(&mailbox);
+            }
+        }
+    };
+    let server = tokio::task::spawn(server);
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let (_, enable) = CommandCodec::default()
+        .decode(b"A1 ENABLE UTF8=ACCEPT\r\n")
+        .unwrap();
+    client.enqueue_command(enable).unwrap();
+    loop {
+        if let ClientFlowEvent::StatusReceived { .. } = client.progress().await.unwrap() {
+            break;
+        }
+    }
+    assert!(client.enabled().utf8_accept);
+
+    // The name is no longer encoded in modified UTF-7.
+    let mailbox = client.enabled().mailbox("Entwürfe").unwrap();
+    let create = Command::new(Tag::unvalidated("A2"), CommandBody::Create { mailbox }).unwrap();
+    client.enqueue_command(create).unwrap();
+    loop {
+        if let ClientFlowEvent::CommandSent { .. } = client.progress().await.unwrap() {
+            break;
+        }
+    }
+
+    assert_eq!(server.await.unwrap().as_deref(), Some("Entwürfe"));
+}
+
+#[tokio::test]
+async fn server_rejects_8bit_before_utf8_is_enabled() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // 8-bit characters are only allowed in literals until `UTF8=ACCEPT` is enabled.
+    client_stream
+        .write_all(b"A1 SELECT \"Entw\xc3\xbcrfe\"\r\nA2 APPEND INBOX {2+}\r\n\xc3\xbc\r\n")
+        .await
+        .unwrap();
+
+    match server.progress().await {
+        Err(ServerFlowError::MalformedMessage { discarded_bytes }) => {
+            assert_eq!(&*discarded_bytes, b"A1 SELECT \"Entw\xc3\xbcrfe\"\r\n");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => assert_eq!(command.tag.as_ref(), "A2"),
+        event => panic!("unexpected event: {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn server_sends_responses_from_sender() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // The task runs while the server is waiting for the client.
+    let mut sender = server.sender();
+    let task = tokio::spawn(async move { sender.enqueue_data(Data::Exists(3)).unwrap() });
+
+    let event = server.progress().await.unwrap();
+    let enqueued_handle = task.await.unwrap();
+    match event {
+        ServerFlowEvent::ResponseSent { handle, .. } => assert_eq!(handle, enqueued_handle),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    let mut line = String::new();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* OK Hello, World!\r\n");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* 3 EXISTS\r\n");
+}
+
+#[tokio::test]
+async fn server_sends_remaining_responses_after_bye() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    let options = ServerFlowOptions {
+        max_batch_size: 16 * 1024,
+        ..Default::default()
+    };
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    let handles = [
+        server.enqueue_data(Data::Exists(1)).unwrap(),
+        server
+            .enqueue_status(Status::bye(None, "Bye").unwrap())
+            .unwrap(),
+        server
+            .enqueue_status(Status::ok(Some(Tag::unvalidated("A1")), None, "done").unwrap())
+            .unwrap(),
+    ];
+
+    // Every response of the batch is reported on its own.
+    for expected_handle in handles {
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::ResponseSent { handle, .. } => assert_eq!(handle, expected_handle),
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    // The flow is closed after the queue was drained.
+    assert!(matches!(
+        server.progress().await,
+        Err(ServerFlowError::Closed)
+    ));
+    assert!(server.is_closed());
+    assert!(server.enqueue_data(Data::Exists(2)).is_err());
+    assert!(server
+        .enqueue_raw(Bytes::from_static(b"* 2 EXISTS\r\n"))
+        .is_err());
+    drop(server);
+
+    let mut output = Vec::new();
+    client_stream.read_to_end(&mut output).await.unwrap();
+    assert_eq!(
+        output,
+        b"* OK Hello, World!\r\n* 1 EXISTS\r\n* BYE Bye\r\nA1 OK done\r\n"
+    );
+}
+
+#[tokio::test]
+async fn flows_logout() {
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let server = async move {
+        let (mut server, _) = ServerFlow::send_greeting(
+            AnyStream::new(server_stream),
+            ServerFlowOptions::default(),
+            Greeting::ok(None, "Hello, World!").unwrap(),
+        )
+        .await
+        .unwrap();
+
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::CommandReceived { command } => {
+                assert_eq!(command.body, CommandBody::Logout);
+                server
+                    .enqueue_status(Status::bye(None, "Logging out").unwrap())
+                    .unwrap();
+                server
+                    .enqueue_status(Status::ok(Some(command.tag), None, "done").unwrap())
+                    .unwrap();
+            }
+            event => panic!("unexpected event: {event:?}"),
+        }
+
+        for _ in 0..2 {
+            match server.progress().await.unwrap() {
+                ServerFlowEvent::ResponseSent { .. } => {}
+                event => panic!("unexpected event: {event:?}"),
+            }
+        }
+
+        assert!(matches!(
+            server.progress().await,
+            Err(ServerFlowError::Closed)
+        ));
+    };
+
+    let client = async move {
+        let (mut client, _) = ClientFlow::receive_greeting(
+            AnyStream::new(client_stream),
+            ClientFlowOptions::default(),
+        )
+        .await
+        .unwrap();
+        client
+            .enqueue_command(Command::new(Tag::unvalidated("A1"), CommandBody::Logout).unwrap())
+            .unwrap();
+
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::CommandSent { .. } => {}
+            event => panic!("unexpected event: {event:?}"),
+        }
+
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::StatusReceived {
+                status: Status::Bye(_),
+            } => {}
+            event => panic!("unexpected event: {event:?}"),
+        }
+        assert_eq!(client.state(), ClientFlowState::Logout);
+        assert!(client
+            .enqueue_command(Command::new(Tag::unvalidated("A2"), CommandBody::Noop).unwrap())
+            .is_err());
+
+        // The tagged status is still received after the `BYE`.
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::StatusReceived {
+                status: Status::Tagged(Tagged { tag, .. }),
+            } => assert_eq!(tag, Tag::unvalidated("A1")),
+            event => panic!("unexpected event: {event:?}"),
+        }
+
+        assert!(matches!(
+            client.progress().await,
+            Err(ClientFlowError::Stream(StreamError::Closed))
+        ));
+        assert_eq!(client.state(), ClientFlowState::Closed);
+        assert!(matches!(
+            client.progress().await,
+            Err(ClientFlowError::Closed)
+        ));
+        assert!(client
+            .enqueue_command(Command::new(Tag::unvalidated("A3"), CommandBody::Noop).unwrap())
+            .is_err());
+    };
+
+    tokio::join!(tokio::task::spawn(server), client).0.unwrap();
+}
+
+#[tokio::test]
+async fn client_reports_unsent_commands_after_bye() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream.write_all(b"* OK Hello\r\n").await.unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    // The synchronizing literal keeps the `LOGIN` in progress until the server answers.
+    let login = CommandCodec::default()
+        .decode(b"A1 LOGIN {5}\r\nalice password\r\n")
+        .unwrap()
+        .1
+        .into_static();
+    let login_handle = client.enqueue_command(login).unwrap();
+    let noop_handle = client
+        .enqueue_command(Command::new(Tag::unvalidated("A2"), CommandBody::Noop).unwrap())
+        .unwrap();
+
+    server_stream
+        .write_all(b"* BYE Shutting down\r\n")
+        .await
+        .unwrap();
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::StatusReceived {
+            status: Status::Bye(_),
+        } => {}
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert_eq!(client.state(), ClientFlowState::Logout);
+
+    for expected_handle in [login_handle, noop_handle] {
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::CommandUnsent { handle, .. } => assert_eq!(handle, expected_handle),
+            event => panic!("unexpected event: {event:?}"),
+        }
+    }
+
+    let mut output = vec![0; 14];
+    server_stream.read_exact(&mut output).await.unwrap();
+    assert_eq!(output, b"A1 LOGIN {5}\r\n");
+}
+
+#[tokio::test]
+async fn client_reports_unsent_commands_after_stream_error() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream.write_all(b"* OK Hello\r\n").await.unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    let handle = client
+        .enqueue_command(Command::new(Tag::unvalidated("A1"), CommandBody::Noop).unwrap())
+        .unwrap();
+
+    drop(server_stream);
+
+    assert!(matches!(
+        client.progress().await,
+        Err(ClientFlowError::Stream(_))
+    ));
+    assert_eq!(client.state(), ClientFlowState::Closed);
+
+    match client.progress().await.unwrap() {
+        ClientFlowEvent::CommandUnsent {
+            handle: unsent_handle,
+            ..
+        } => assert_eq!(unsent_handle, handle),
+        event => panic!("unexpected event: {event:?}"),
+    }
+    assert!(matches!(
+        client.progress().await,
+        Err(ClientFlowError::Closed)
+    ));
+    assert!(client
+        .enqueue_command(Command::new(Tag::unvalidated("A2"), CommandBody::Noop).unwrap())
+        .is_err());
+}
+
+#[tokio::test]
+async fn server_sends_raw_responses() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    let bytes = Bytes::from_static(b"* 1 FETCH (BODY[] {5}\r\nhello)\r\n");
+    let enqueued_handle = server.enqueue_raw(bytes.clone()).unwrap();
+
+    match server.progress().await.unwrap() {
+        ServerFlowEvent::RawResponseSent {
+            handle,
+            bytes: sent,
+        } => {
+            assert_eq!(handle, enqueued_handle);
+            assert_eq!(sent, bytes);
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    let mut line = String::new();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* OK Hello, World!\r\n");
+
+    line.clear();
+    client_stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "* 1 FETCH (BODY[] {5}\r\n");
+}
+
+#[tokio::test]
+async fn server_streams_literal_from_reader() {
+    let (server_stream, mut client_stream) = tokio::io::duplex(1024);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // The body is larger than the duplex buffer, so it must be sent in chunks.
+    let body = vec![b'x'; 100_000];
+    let literal = LiteralReader::new(body.len() as u32, std::io::Cursor::new(body.clone()));
+    let enqueued_handle = server
+        .enqueue_streamed(
+            Bytes::from_static(b"* 1 FETCH (BODY[] "),
+            literal,
+            Bytes::from_static(b")\r\n"),
+        )
+        .unwrap();
+
+    let client = async move {
+        let mut output = Vec::new();
+        client_stream.read_to_end(&mut output).await.unwrap();
+        output
+    };
+
+    let server = async move {
+        match server.progress().await.unwrap() {
+            ServerFlowEvent::StreamedResponseSent { handle } => assert_eq!(handle, enqueued_handle),
+            event => panic!("unexpected event: {event:?}"),
+        }
+    };
+
+    let (output, ()) = tokio::join!(client, server);
+
+    let mut expected = b"* OK Hello, World!\r\n* 1 FETCH (BODY[] {100000}\r\n".to_vec();
+    expected.extend_from_slice(&body);
+    expected.extend_from_slice(b")\r\n");
+    assert_eq!(output, expected);
+}
+
+#[cfg(feature = "client_driver")]
+#[tokio::test]
+async fn client_driver_resolves_commands() {
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+    let mut server_stream = BufReader::new(server_stream);
+    server_stream.write_all(b"* OK Hello\r\n").await.unwrap();
+
+    let (client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    let (handle, mut unsolicited) = imap_flow::client_driver::spawn(client);
+
+    let server = async move {
+        let mut line = String::new();
+        server_stream.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "A1 NOOP\r\n");
+
+        server_stream
+            .write_all(b"* 3 EXISTS\r\nA1 OK done\r\n* 4 EXISTS\r\n")
+            .await
+            .unwrap();
+
+        server_stream
+    };
+
+    let command = Command::new(Tag::unvalidated("A1"), CommandBody::Noop).unwrap();
+    let (response, _server_stream) = tokio::join!(handle.send(command), server);
+    let response = response.unwrap();
+    assert!(matches!(response.status, Status::Tagged(_)));
+    assert!(matches!(response.data.as_slice(), [Data::Exists(3)]));
+
+    assert!(matches!(
+        unsolicited.recv().await,
+        Some(Response::Data(Data::Exists(4)))
+    ));
+}
+
+#[cfg(feature = "event_stream")]
+#[tokio::test]
+async fn server_flow_stream_exposes_flow_and_handles() {
+    use std::pin::Pin;
+
+    use futures_core::Stream;
+    use imap_flow::event_stream::ServerFlowStream;
+
+    async fn next_event<S: Stream + Unpin>(stream: &mut S) -> S::Item {
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx))
+            .await
+            .unwrap()
+    }
+
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+    let mut client_stream = BufReader::new(client_stream);
+
+    let options = ServerFlowOptions {
+        announce_literals: true,
+        ..Default::default()
+    };
+    let (server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        options,
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+    let (mut stream, mut sink) = ServerFlowStream::new(server);
+
+    client_stream
+        .write_all(b"A1 APPEND INBOX {5}\r\n")
+        .await
+        .unwrap();
+
+    match next_event(&mut stream).await.unwrap() {
+        ServerFlowEvent::LiteralAnnounced { length, .. } => assert_eq!(length, 5),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    // The flow is idle until the stream is polled again.
+    stream.flow_mut().unwrap().literal_accept().unwrap();
+    client_stream.write_all(b"hello\r\n").await.unwrap();
+
+    match next_event(&mut stream).await.unwrap() {
+        ServerFlowEvent::CommandReceived { command } => {
+            assert_eq!(command.tag, Tag::unvalidated("A1"));
+        }
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    let status = Status::ok(Some(Tag::unvalidated("A1")), None, "done").unwrap();
+    let enqueued_handle = sink.enqueue_response(Response::Status(status)).unwrap();
+
+    match next_event(&mut stream).await.unwrap() {
+        ServerFlowEvent::ResponseSent { handle, .. } => assert_eq!(handle, enqueued_handle),
+        event => panic!("unexpected event: {event:?}"),
+    }
+
+    let mut output = String::new();
+    for _ in 0..3 {
+        client_stream.read_line(&mut output).await.unwrap();
+    }
+    assert_eq!(output, "* OK Hello, World!\r\n+ ...\r\nA1 OK done\r\n");
+}
+
+#[tokio::test]
+async fn client_shutdown_returns_unsent_commands() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+
+    server_stream
+        .write_all(b"* OK Hello, World!\r\n")
+        .await
+        .unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+
+    let authenticate = Command::new(
+        Tag::unvalidated("A1"),
+        CommandBody::Authenticate {
+            mechanism: AuthMechanism::Plain,
+            initial_response: None,
+        },
+    )
+    .unwrap();
+    let authenticate_handle = client.enqueue_command(authenticate).unwrap();
+    let noop = Command::new(Tag::unvalidated("A2"), CommandBody::Noop).unwrap();
+    let noop_handle = client.enqueue_command(noop).unwrap();
+
+    // The `AUTHENTICATE` needs interaction with the flow user, so the shutdown stops there.
+    let shutdown = client.shutdown(false).await.unwrap();
+    match shutdown.events.as_slice() {
+        [ClientFlowEvent::AuthenticateStarted { .. }, ClientFlowEvent::CommandUnsent {
+            handle: first_handle,
+            ..
+        }, ClientFlowEvent::CommandUnsent {
+            handle: second_handle,
+            ..
+        }] => {
+            assert_eq!(*first_handle, authenticate_handle);
+            assert_eq!(*second_handle, noop_handle);
+        }
+        events => panic!("unexpected events: {events:?}"),
+    }
+    assert_eq!(shutdown.state, ClientFlowState::NotAuthenticated);
+    assert_eq!(shutdown.statistics.commands_sent, 1);
+
+    let mut output = Vec::new();
+    server_stream.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output, b"A1 AUTHENTICATE PLAIN\r\n");
+}
+
+#[tokio::test]
+async fn server_shutdown_sends_bye() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+
+    let server = async move {
+        let (server, _) = ServerFlow::send_greeting(
+            AnyStream::new(server_stream),
+            ServerFlowOptions::default(),
+            Greeting::ok(None, "Hello, World!").unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let bye = Bye {
+            code: None,
+            text: Text::try_from("Shutting down").unwrap(),
+        };
+        let shutdown = server.shutdown(Some(bye), false).await.unwrap();
+        assert!(!shutdown.closed);
+        assert!(matches!(
+            shutdown.events.as_slice(),
+            [ServerFlowEvent::ResponseSent {
+                response: Response::Status(Status::Bye(_)),
+                ..
+            }]
+        ));
+    };
+
+    let client = async move {
+        let (mut client, _) = ClientFlow::receive_greeting(
+            AnyStream::new(client_stream),
+            ClientFlowOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        match client.progress().await.unwrap() {
+            ClientFlowEvent::StatusReceived {
+                status: Status::Bye(_),
+            } => {}
+            event => panic!("unexpected event: {event:?}"),
+        }
+
+        assert!(matches!(
+            client.progress().await,
+            Err(ClientFlowError::Stream(StreamError::Closed))
+        ));
+    };
+
+    tokio::join!(server, client);
+}
+
+#[tokio::test]
+async fn client_shutdown_returns_unsent_commands_on_error() {
+    let (client_stream, mut server_stream) = tokio::io::duplex(1024);
+    server_stream.write_all(b"* OK Hello\r\n").await.unwrap();
+
+    let (mut client, _) =
+        ClientFlow::receive_greeting(AnyStream::new(client_stream), ClientFlowOptions::default())
+            .await
+            .unwrap();
+    let handle = client
+        .enqueue_command(Command::new(Tag::unvalidated("A1"), CommandBody::Noop).unwrap())
+        .unwrap();
+
+    drop(server_stream);
+
+    let error = client.shutdown(false).await.unwrap_err();
+    assert!(matches!(error.error, ClientFlowError::Stream(_)));
+    assert_eq!(error.shutdown.state, ClientFlowState::Closed);
+    match error.shutdown.events.as_slice() {
+        [ClientFlowEvent::CommandUnsent {
+            handle: unsent_handle,
+            ..
+        }] => assert_eq!(*unsent_handle, handle),
+        events => panic!("unexpected events: {events:?}"),
+    }
+}
+
+#[tokio::test]
+async fn server_shutdown_returns_unsent_responses_on_error() {
+    let (server_stream, client_stream) = tokio::io::duplex(1024);
+
+    let (mut server, _) = ServerFlow::send_greeting(
+        AnyStream::new(server_stream),
+        ServerFlowOptions::default(),
+        Greeting::ok(None, "Hello, World!").unwrap(),
+    )
+    .await
+    .unwrap();
+    let handle = server
+        .enqueue_status(Status::ok(None, None, "still there?").unwrap())
+        .unwrap();
+
+    drop(client_stream);
+
+    let error = server.shutdown(None, false).await.unwrap_err();
+    assert!(matches!(error.error, ServerFlowError::Stream(_)));
+    assert!(!error.shutdown.closed);
+    match error.shutdown.events.as_slice() {
+        [ServerFlowEvent::ResponseUnsent {
+            handle: unsent_handle,
+            ..
+        }] => assert_eq!(*unsent_handle, handle),
+        events => panic!("unexpected events: {events:?}"),
+    }
+}